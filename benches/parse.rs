@@ -0,0 +1,41 @@
+use std::hint::black_box;
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use rust_calculator::parser;
+
+// Short expressions of the kind typed into the REPL, heavy on number literals.
+const CORPUS: &[&str] = &[
+    "1 + 2 * 3",
+    "3.14159 * 2.71828 - 1.41421 / 1.73205",
+    "1_000_000 + 250_000.5 - 0.000_1",
+    "0xff & 0b1010 | 0o17",
+    "sqrt(16) + abs(-4.5) + floor(7.9)",
+    "max(1, 2, 3, 4, 5, 6, 7, 8, 9, 10)",
+    "2^10 - 1e3 + 6.02e23 / 1.5e-3",
+    "x = 12.5; y = x * 3.75; x + y",
+    "(1.5 + 2.5) * (3.5 - 4.5) / (5.5 + 6.5)",
+    "2pi * 6371.0088",
+];
+
+fn tokenize(c: &mut Criterion) {
+    c.bench_function("tokenize", |b| {
+        b.iter(|| {
+            for input in CORPUS {
+                let _ = black_box(parser::tokenize_spanned(black_box(input)));
+            }
+        })
+    });
+}
+
+fn parse(c: &mut Criterion) {
+    c.bench_function("parse_program", |b| {
+        b.iter(|| {
+            for input in CORPUS {
+                let _ = black_box(parser::parse_program(black_box(input)));
+            }
+        })
+    });
+}
+
+criterion_group!(benches, tokenize, parse);
+criterion_main!(benches);
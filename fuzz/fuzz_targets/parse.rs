@@ -0,0 +1,16 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rust_calculator::parser;
+
+// Parsing must return a `Result` for any input rather than panic, and so must
+// evaluating and printing whatever parses.
+fuzz_target!(|input: &str| {
+    let _ = parser::parse_collect(input);
+    if let Ok(program) = parser::parse_program(input) {
+        for expr in program {
+            let _ = expr.eval();
+            let _ = expr.to_string();
+        }
+    }
+});
@@ -0,0 +1,153 @@
+use rust_calculator::{parser, render_error, AngleMode};
+use thiserror::Error;
+
+// A REPL line starting with `:`, handled by the REPL itself rather than the
+// parser.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Command {
+    Help,
+    Quit,
+    Vars,
+    Clear,
+    Precision(Option<usize>),
+    Angle(AngleMode),
+    // The expression whose tree `:ast` prints.
+    Ast(String),
+}
+
+#[derive(Error, Debug)]
+pub enum CommandError {
+    #[error("Unknown command: {0:?}")]
+    UnknownCommand(String),
+
+    #[error("Invalid argument for :{0}: {1:?}")]
+    InvalidArgument(&'static str, String),
+}
+
+// More decimals than an f64 holds digits only print noise, and `format!`
+// panics on precisions past `u16::MAX`.
+const MAX_PRECISION: usize = 17;
+
+pub const HELP: &str = "\
+:help            show this message
+:quit            leave the calculator
+:vars            list the defined variables
+:clear           forget every variable and function, including ans
+:precision [n]   round results to n (at most 17) decimals, or print every digit
+:deg, :rad       read trig arguments as degrees or radians
+:ast <expr>      print the parsed tree of expr without evaluating it";
+
+impl Command {
+    // Returns `None` for lines that are not commands.
+    pub fn parse(line: &str) -> Option<Result<Command, CommandError>> {
+        let line = line.trim().strip_prefix(':')?;
+        let (name, arg) = match line.split_once(char::is_whitespace) {
+            Some((name, arg)) => (name, arg.trim()),
+            None => (line, ""),
+        };
+        let command = match (name, arg) {
+            ("help", "") => Command::Help,
+            ("quit", "") => Command::Quit,
+            ("vars", "") => Command::Vars,
+            ("clear", "") => Command::Clear,
+            ("precision", "") => Command::Precision(None),
+            ("precision", arg) => match arg.parse() {
+                Ok(precision) if precision <= MAX_PRECISION => Command::Precision(Some(precision)),
+                _ => {
+                    return Some(Err(CommandError::InvalidArgument(
+                        "precision",
+                        arg.to_owned(),
+                    )))
+                }
+            },
+            ("deg", "") => Command::Angle(AngleMode::Degrees),
+            ("rad", "") => Command::Angle(AngleMode::Radians),
+            ("ast", arg) => Command::Ast(arg.to_owned()),
+            _ => return Some(Err(CommandError::UnknownCommand(line.to_owned()))),
+        };
+        Some(Ok(command))
+    }
+}
+
+// What `:ast` prints: the parsed tree as an S-expression, so `1 + 2 * 3`
+// gives `(+ 1 (* 2 3))`, or the parse error with a caret under it.
+pub fn ast(input: &str) -> Result<String, String> {
+    parser::parse(input)
+        .map(|expr| expr.to_sexpr())
+        .map_err(|err| render_error(input, &err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ast_prints_the_tree() {
+        assert_eq!(ast("1 + 2 * 3").unwrap(), "(+ 1 (* 2 3))");
+        assert_eq!(ast("-x ^ 2").unwrap(), "(neg (^ x 2))");
+        assert_eq!(ast("(-x) ^ 2").unwrap(), "(^ (neg x) 2)");
+    }
+
+    #[test]
+    fn ast_takes_the_rest_of_the_line() {
+        assert_eq!(
+            Command::parse(":ast 1 + 2 * 3").unwrap().unwrap(),
+            Command::Ast("1 + 2 * 3".to_owned())
+        );
+    }
+
+    #[test]
+    fn ast_renders_parse_errors() {
+        let err = ast("1 +").unwrap_err();
+        assert_eq!(
+            err,
+            "Unexpected end of input, expected an operand\n1 +\n   ^"
+        );
+    }
+
+    #[test]
+    fn precision_takes_at_most_17_decimals() {
+        assert!(matches!(
+            Command::parse(":precision 4"),
+            Some(Ok(Command::Precision(Some(4))))
+        ));
+        assert!(matches!(
+            Command::parse(":precision"),
+            Some(Ok(Command::Precision(None)))
+        ));
+        for arg in ["18", "70000", "-1", "two"] {
+            assert!(matches!(
+                Command::parse(&format!(":precision {arg}")),
+                Some(Err(CommandError::InvalidArgument("precision", invalid))) if invalid == arg
+            ));
+        }
+    }
+
+    #[test]
+    fn parses_commands() {
+        let parse = |line| Command::parse(line).unwrap().unwrap();
+        assert_eq!(parse(":help"), Command::Help);
+        assert_eq!(parse(":quit"), Command::Quit);
+        assert_eq!(parse("  :vars  "), Command::Vars);
+        assert_eq!(parse(":clear"), Command::Clear);
+        assert_eq!(parse(":deg"), Command::Angle(AngleMode::Degrees));
+    }
+
+    #[test]
+    fn lines_without_a_colon_are_not_commands() {
+        assert!(Command::parse("1 + 2").is_none());
+        assert!(Command::parse("x = 3").is_none());
+    }
+
+    #[test]
+    fn rejects_unknown_commands_and_stray_arguments() {
+        assert!(matches!(
+            Command::parse(":frobnicate"),
+            Some(Err(CommandError::UnknownCommand(name))) if name == "frobnicate"
+        ));
+        assert!(matches!(
+            Command::parse(":quit now"),
+            Some(Err(CommandError::UnknownCommand(_)))
+        ));
+    }
+}
@@ -0,0 +1,281 @@
+use std::{
+    fmt::{self, Display, Formatter},
+    ops::{Add, Mul, Neg, Sub},
+};
+
+use crate::{
+    error::{Error, Result},
+    eval::{apply, arity, Environment},
+    parser::Expr,
+};
+
+// The result of `eval_complex`. A real result has a zero imaginary part and
+// displays as a plain number.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Complex {
+    pub re: f64,
+    pub im: f64,
+}
+
+impl Complex {
+    pub const I: Complex = Complex { re: 0.0, im: 1.0 };
+
+    pub fn new(re: f64, im: f64) -> Self {
+        Complex { re, im }
+    }
+
+    pub fn is_real(&self) -> bool {
+        self.im == 0.0
+    }
+
+    pub fn abs(self) -> f64 {
+        self.re.hypot(self.im)
+    }
+
+    pub fn arg(self) -> f64 {
+        self.im.atan2(self.re)
+    }
+
+    fn is_zero(self) -> bool {
+        self.re == 0.0 && self.im == 0.0
+    }
+
+    fn div(self, rhs: Complex) -> Result<Complex> {
+        let denominator = rhs.re * rhs.re + rhs.im * rhs.im;
+        if denominator == 0.0 {
+            return Err(Error::DivisionByZero());
+        }
+        Ok(Complex {
+            re: (self.re * rhs.re + self.im * rhs.im) / denominator,
+            im: (self.im * rhs.re - self.re * rhs.im) / denominator,
+        })
+    }
+
+    // The principal square root, with a non-negative real part.
+    fn sqrt(self) -> Complex {
+        let abs = self.abs();
+        let im = ((abs - self.re) / 2.0).sqrt();
+        Complex {
+            re: ((abs + self.re) / 2.0).sqrt(),
+            im: if self.im < 0.0 { -im } else { im },
+        }
+    }
+
+    fn ln(self) -> Result<Complex> {
+        if self.is_zero() {
+            return Err(Error::OutOfDomain("ln".to_owned()));
+        }
+        Ok(Complex {
+            re: self.abs().ln(),
+            im: self.arg(),
+        })
+    }
+
+    fn exp(self) -> Complex {
+        let scale = self.re.exp();
+        Complex {
+            re: scale * self.im.cos(),
+            im: scale * self.im.sin(),
+        }
+    }
+
+    // Integral real exponents multiply out, so `(1 + 2i)^2` is exactly
+    // `-3 + 4i`; anything else goes through `exp(exp * ln(self))`.
+    fn pow(self, exp: Complex) -> Result<Complex> {
+        if exp.is_real() && exp.re.fract() == 0.0 && exp.re.abs() <= i32::MAX as f64 {
+            let mut n = exp.re.abs() as u32;
+            let mut base = self;
+            let mut result = Complex::from(1.0);
+            while n > 0 {
+                if n & 1 == 1 {
+                    result = result * base;
+                }
+                base = base * base;
+                n >>= 1;
+            }
+            if exp.re < 0.0 {
+                return Complex::from(1.0).div(result);
+            }
+            return Ok(result);
+        }
+        if self.is_zero() {
+            return Err(Error::OutOfDomain("^".to_owned()));
+        }
+        Ok((exp * self.ln()?).exp())
+    }
+}
+
+impl From<f64> for Complex {
+    fn from(re: f64) -> Self {
+        Complex { re, im: 0.0 }
+    }
+}
+
+impl Add for Complex {
+    type Output = Complex;
+
+    fn add(self, rhs: Complex) -> Complex {
+        Complex::new(self.re + rhs.re, self.im + rhs.im)
+    }
+}
+
+impl Sub for Complex {
+    type Output = Complex;
+
+    fn sub(self, rhs: Complex) -> Complex {
+        Complex::new(self.re - rhs.re, self.im - rhs.im)
+    }
+}
+
+impl Mul for Complex {
+    type Output = Complex;
+
+    fn mul(self, rhs: Complex) -> Complex {
+        Complex::new(
+            self.re * rhs.re - self.im * rhs.im,
+            self.re * rhs.im + self.im * rhs.re,
+        )
+    }
+}
+
+impl Neg for Complex {
+    type Output = Complex;
+
+    fn neg(self) -> Complex {
+        Complex::new(-self.re, -self.im)
+    }
+}
+
+// Written so that it parses back: `3`, `2i`, `1 + 2i`, `1 - 2i`.
+impl Display for Complex {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let Complex { re, im } = *self;
+        if im == 0.0 {
+            write!(f, "{re}")
+        } else if re == 0.0 {
+            write!(f, "{im}i")
+        } else if im < 0.0 {
+            write!(f, "{re} - {}i", -im)
+        } else {
+            write!(f, "{re} + {im}i")
+        }
+    }
+}
+
+impl Expr {
+    // Evaluates over the complex numbers, reading `i` as the imaginary unit
+    // unless a variable `i` is defined. Signs, `+`, `-`, `*`, `/`, `^`, `==`,
+    // `!=`, `sqrt`, `abs` and `ln` accept complex operands; every other
+    // operator and function requires real ones, and variables hold only
+    // reals. `sqrt(-1)` and `ln(-1)` are complex rather than out of domain.
+    pub fn eval_complex(&self) -> Result<Complex> {
+        self.eval_complex_in(&mut Environment::new())
+    }
+
+    pub fn eval_complex_in(&self, env: &mut Environment) -> Result<Complex> {
+        self.walk(env, apply_complex, |value| !value.is_zero())
+    }
+}
+
+// Real operands go through the `f64` evaluator unless the result is only
+// defined as a complex number.
+fn apply_complex(expr: &Expr, values: &mut Vec<Complex>, env: &mut Environment) -> Result<Complex> {
+    let operands = values.len() - arity(expr);
+    if let Expr::Var(name) = expr {
+        if name == "i" && env.get(name).is_none() {
+            return Ok(Complex::I);
+        }
+    }
+    let real = values[operands..].iter().all(Complex::is_real);
+    let value = if real && !complex_result(expr, &values[operands..]) {
+        let mut floats: Vec<f64> = values[operands..].iter().map(|value| value.re).collect();
+        Complex::from(apply(expr, &mut floats, env)?)
+    } else {
+        complex_op(expr, &values[operands..])?
+    };
+    values.truncate(operands);
+    Ok(value)
+}
+
+fn complex_result(expr: &Expr, operands: &[Complex]) -> bool {
+    match (expr, operands) {
+        (Expr::Call { name, .. }, [x]) => (name == "sqrt" || name == "ln") && x.re < 0.0,
+        (Expr::Pow { .. }, [base, exp]) => base.re < 0.0 && exp.re.fract() != 0.0,
+        _ => false,
+    }
+}
+
+fn complex_op(expr: &Expr, operands: &[Complex]) -> Result<Complex> {
+    Ok(match (expr, operands) {
+        (Expr::Plus(_) | Expr::Cond { .. }, &[value]) => value,
+        (Expr::Minus(_), &[value]) => -value,
+        (Expr::Add { .. }, &[lhs, rhs]) => lhs + rhs,
+        (Expr::Sub { .. }, &[lhs, rhs]) => lhs - rhs,
+        (Expr::Mul { .. }, &[lhs, rhs]) => lhs * rhs,
+        (Expr::Div { .. }, &[lhs, rhs]) => lhs.div(rhs)?,
+        (Expr::Pow { .. }, &[base, exp]) => base.pow(exp)?,
+        (Expr::Eq { .. }, &[lhs, rhs]) => Complex::from(f64::from(u8::from(lhs == rhs))),
+        (Expr::Ne { .. }, &[lhs, rhs]) => Complex::from(f64::from(u8::from(lhs != rhs))),
+        (Expr::Call { name, .. }, &[x]) if name == "sqrt" => x.sqrt(),
+        (Expr::Call { name, .. }, &[x]) if name == "abs" => Complex::from(x.abs()),
+        (Expr::Call { name, .. }, &[x]) if name == "ln" => x.ln()?,
+        _ => return Err(Error::ComplexOperand()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+
+    fn complex(input: &str) -> Complex {
+        parse(input).unwrap().eval_complex().unwrap()
+    }
+
+    #[test]
+    fn complex_arithmetic() {
+        assert_eq!(complex("(1 + 2i) + (3 - 4i)"), Complex::new(4.0, -2.0));
+        assert_eq!(complex("(1 + 2i) * (3 - 4i)"), Complex::new(11.0, 2.0));
+        assert_eq!(complex("i * i"), Complex::from(-1.0));
+        assert_eq!(complex("(1 + 2i) ^ 2"), Complex::new(-3.0, 4.0));
+        assert_eq!(complex("(4 + 2i) / 2"), Complex::new(2.0, 1.0));
+    }
+
+    #[test]
+    fn square_roots_of_negative_numbers_are_imaginary() {
+        assert_eq!(complex("sqrt(-1)"), Complex::I);
+        assert_eq!(complex("sqrt(-1) == i"), Complex::from(1.0));
+        assert_eq!(complex("sqrt(-4)"), Complex::new(0.0, 2.0));
+    }
+
+    #[test]
+    fn real_expressions_stay_real() {
+        assert_eq!(complex("2 + 3"), Complex::from(5.0));
+        assert!(complex("abs(3 + 4i)").is_real());
+    }
+
+    #[test]
+    fn a_variable_named_i_shadows_the_unit() {
+        let mut env = Environment::new();
+        env.set("i", 3.0);
+        let value = parse("2i").unwrap().eval_complex_in(&mut env).unwrap();
+        assert_eq!(value, Complex::from(6.0));
+    }
+
+    #[test]
+    fn real_only_operations_reject_complex_operands() {
+        let result = parse("floor(i)").unwrap().eval_complex();
+        assert!(matches!(result, Err(Error::ComplexOperand())));
+    }
+
+    #[test]
+    fn display_parses_back() {
+        for value in [
+            Complex::new(1.0, 2.0),
+            Complex::new(1.0, -2.0),
+            Complex::new(0.0, 2.0),
+        ] {
+            assert_eq!(complex(&value.to_string()), value);
+        }
+    }
+}
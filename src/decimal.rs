@@ -0,0 +1,152 @@
+use std::str::FromStr;
+
+use bigdecimal::{BigDecimal, ToPrimitive, Zero};
+
+use crate::{
+    error::{Error, Result},
+    eval::{apply, arity, Environment},
+    parser::Expr,
+};
+
+// `Expr`, `Token` and `Environment` hold `f64` whatever the features; this
+// is not a numeric type parameter and not arbitrary precision. It only runs
+// the operators below on `BigDecimal`, which makes their results exact decimal
+// sums, differences and products of the `f64` operands.
+impl Expr {
+    // Evaluates the exact operators in decimal arithmetic, so
+    // `0.1 + 0.2 == 0.3` is 1. Signs, `+`, `-`, `*`, `/`, comparisons,
+    // conditionals and assignments are exact; division stops at 100
+    // significant digits. Every other operator and function goes through the
+    // `f64` evaluator.
+    //
+    // Literals are still lexed into the `f64` of `Expr::Num` and read back
+    // as the shortest decimal that rounds to it. That is the literal as
+    // written for up to 15 significant digits, but longer ones lose what the
+    // `f64` cannot hold: `0.12345678901234567891 * 1` is
+    // `0.12345678901234568`.
+    pub fn eval_decimal_ops(&self) -> Result<BigDecimal> {
+        self.eval_decimal_ops_in(&mut Environment::new())
+    }
+
+    // Variables are stored as `f64`, so a value assigned here reads back as
+    // the shortest decimal that rounds to the same `f64`.
+    pub fn eval_decimal_ops_in(&self, env: &mut Environment) -> Result<BigDecimal> {
+        self.walk(env, apply_decimal, |value| !value.is_zero())
+    }
+}
+
+// Applies the operators that decimals keep exact and falls back to the `f64`
+// evaluator for the rest, converting its result back to a decimal.
+fn apply_decimal(
+    expr: &Expr,
+    values: &mut Vec<BigDecimal>,
+    env: &mut Environment,
+) -> Result<BigDecimal> {
+    let operands = values.len() - arity(expr);
+    if let Some(value) = apply_exact(expr, &values[operands..], env)? {
+        values.truncate(operands);
+        return Ok(value);
+    }
+    let mut floats: Vec<f64> = values
+        .drain(operands..)
+        .map(|value| float(&value))
+        .collect();
+    decimal(apply(expr, &mut floats, env)?)
+}
+
+fn apply_exact(
+    expr: &Expr,
+    operands: &[BigDecimal],
+    env: &mut Environment,
+) -> Result<Option<BigDecimal>> {
+    let value = match (expr, operands) {
+        (Expr::Plus(_) | Expr::Cond { .. }, [value]) => value.clone(),
+        (Expr::Minus(_), [value]) => -value,
+        (Expr::Assign { name, .. }, [value]) => {
+            env.set(name.clone(), float(value));
+            value.clone()
+        }
+        (Expr::Add { .. }, [lhs, rhs]) => lhs + rhs,
+        (Expr::Sub { .. }, [lhs, rhs]) => lhs - rhs,
+        (Expr::Mul { .. }, [lhs, rhs]) => lhs * rhs,
+        (Expr::Div { .. }, [lhs, rhs]) => {
+            if rhs.is_zero() {
+                return Err(Error::DivisionByZero());
+            }
+            lhs / rhs
+        }
+        (Expr::Eq { .. }, [lhs, rhs]) => truth(lhs == rhs),
+        (Expr::Ne { .. }, [lhs, rhs]) => truth(lhs != rhs),
+        (Expr::Lt { .. }, [lhs, rhs]) => truth(lhs < rhs),
+        (Expr::Le { .. }, [lhs, rhs]) => truth(lhs <= rhs),
+        (Expr::Gt { .. }, [lhs, rhs]) => truth(lhs > rhs),
+        (Expr::Ge { .. }, [lhs, rhs]) => truth(lhs >= rhs),
+        _ => return Ok(None),
+    };
+    Ok(Some(value))
+}
+
+// The shortest decimal that rounds to `value`, which for a literal is the
+// number as written: `0.1` rather than `0.1000000000000000055511151231257827`.
+// Infinities and NaN have no decimal form.
+fn decimal(value: f64) -> Result<BigDecimal> {
+    if value.is_nan() {
+        return Err(Error::NotANumber());
+    }
+    if value.is_infinite() {
+        return Err(Error::Overflow());
+    }
+    BigDecimal::from_str(&value.to_string()).map_err(|_| Error::NotANumber())
+}
+
+fn float(value: &BigDecimal) -> f64 {
+    value.to_f64().unwrap_or(f64::NAN)
+}
+
+fn truth(value: bool) -> BigDecimal {
+    BigDecimal::from(u8::from(value))
+}
+
+#[cfg(test)]
+mod tests {
+    use bigdecimal::BigDecimal;
+    use std::str::FromStr;
+
+    use crate::{error::Error, parser::parse};
+
+    fn decimal(input: &str) -> BigDecimal {
+        parse(input).unwrap().eval_decimal_ops().unwrap()
+    }
+
+    #[test]
+    fn decimal_arithmetic_is_exact() {
+        assert_eq!(decimal("0.1 + 0.2"), BigDecimal::from_str("0.3").unwrap());
+        assert_eq!(decimal("0.1 + 0.2 == 0.3"), BigDecimal::from(1));
+        assert_eq!(decimal("10 - 9.99"), BigDecimal::from_str("0.01").unwrap());
+        assert_eq!(
+            decimal("1e20 + 1"),
+            BigDecimal::from_str("100000000000000000001").unwrap()
+        );
+    }
+
+    #[test]
+    fn other_operations_go_through_f64() {
+        assert_eq!(
+            decimal("sqrt(2)"),
+            BigDecimal::from_str(&2f64.sqrt().to_string()).unwrap()
+        );
+        assert!(matches!(
+            parse("1 / 0").unwrap().eval_decimal_ops(),
+            Err(Error::DivisionByZero())
+        ));
+    }
+
+    // Literals are lexed as `f64`, so digits past what one holds are lost.
+    #[test]
+    fn long_literals_are_rounded_to_f64() {
+        assert_eq!(
+            decimal("0.12345678901234567891 * 1"),
+            BigDecimal::from_str("0.12345678901234568").unwrap()
+        );
+    }
+}
@@ -0,0 +1,165 @@
+use crate::{
+    error::{Error, Result},
+    parser::Expr,
+};
+
+impl Expr {
+    // The derivative with respect to `var`, unsimplified: `x * x` gives
+    // `1 * x + x * 1`, so run the result through `simplify`. Trig functions
+    // are differentiated as if their arguments were radians.
+    pub fn derivative(&self, var: &str) -> Result<Expr> {
+        Ok(match self {
+            Expr::Num(_) => Expr::Num(0.0),
+            Expr::Var(name) => Expr::Num(if name == var { 1.0 } else { 0.0 }),
+            Expr::Plus(expr) => expr.derivative(var)?,
+            Expr::Minus(expr) => Expr::Minus(Box::new(expr.derivative(var)?)),
+            Expr::Add { lhs, rhs } => add(lhs.derivative(var)?, rhs.derivative(var)?),
+            Expr::Sub { lhs, rhs } => sub(lhs.derivative(var)?, rhs.derivative(var)?),
+            Expr::Mul { lhs, rhs } => add(
+                mul(lhs.derivative(var)?, *rhs.clone()),
+                mul(*lhs.clone(), rhs.derivative(var)?),
+            ),
+            Expr::Div { lhs, rhs } => div(
+                sub(
+                    mul(lhs.derivative(var)?, *rhs.clone()),
+                    mul(*lhs.clone(), rhs.derivative(var)?),
+                ),
+                pow(*rhs.clone(), Expr::Num(2.0)),
+            ),
+            // The power rule when the exponent does not depend on `var`,
+            // otherwise the general rule through `ln`.
+            Expr::Pow { base, exp } if !exp.vars().contains(var) => mul(
+                mul(
+                    *exp.clone(),
+                    pow(*base.clone(), sub(*exp.clone(), Expr::Num(1.0))),
+                ),
+                base.derivative(var)?,
+            ),
+            Expr::Pow { base, exp } => mul(
+                self.clone(),
+                add(
+                    mul(exp.derivative(var)?, call("ln", *base.clone())),
+                    div(mul(*exp.clone(), base.derivative(var)?), *base.clone()),
+                ),
+            ),
+            Expr::Cond {
+                test,
+                then,
+                otherwise,
+            } => Expr::Cond {
+                test: test.clone(),
+                then: Box::new(then.derivative(var)?),
+                otherwise: Box::new(otherwise.derivative(var)?),
+            },
+            Expr::Call { name, args } => match (name.as_str(), args.as_slice()) {
+                // `deg` and `rad` are linear.
+                ("deg" | "rad", [arg]) => call(name, arg.derivative(var)?),
+                ("sqrt", [arg]) => div(arg.derivative(var)?, mul(Expr::Num(2.0), self.clone())),
+                ("abs", [arg]) => div(mul(arg.clone(), arg.derivative(var)?), self.clone()),
+                ("sin", [arg]) => mul(call("cos", arg.clone()), arg.derivative(var)?),
+                ("cos", [arg]) => Expr::Minus(Box::new(mul(
+                    call("sin", arg.clone()),
+                    arg.derivative(var)?,
+                ))),
+                ("tan", [arg]) => div(
+                    arg.derivative(var)?,
+                    pow(call("cos", arg.clone()), Expr::Num(2.0)),
+                ),
+                ("ln", [arg]) => div(arg.derivative(var)?, arg.clone()),
+                ("log", [base, arg]) if !base.vars().contains(var) => div(
+                    arg.derivative(var)?,
+                    mul(arg.clone(), call("ln", base.clone())),
+                ),
+                _ => return Err(Error::NotDifferentiable(name.clone())),
+            },
+            Expr::Factorial(_) => return Err(Error::NotDifferentiable("!".to_owned())),
+            Expr::Assign { .. } | Expr::Define { .. } => {
+                return Err(Error::NotDifferentiable("=".to_owned()))
+            }
+            _ => {
+                let Some((op, _, _)) = self.binary_parts() else {
+                    unreachable!("every other node is binary");
+                };
+                return Err(Error::NotDifferentiable(op.to_owned()));
+            }
+        })
+    }
+}
+
+fn add(lhs: Expr, rhs: Expr) -> Expr {
+    Expr::Add {
+        lhs: Box::new(lhs),
+        rhs: Box::new(rhs),
+    }
+}
+
+fn sub(lhs: Expr, rhs: Expr) -> Expr {
+    Expr::Sub {
+        lhs: Box::new(lhs),
+        rhs: Box::new(rhs),
+    }
+}
+
+fn mul(lhs: Expr, rhs: Expr) -> Expr {
+    Expr::Mul {
+        lhs: Box::new(lhs),
+        rhs: Box::new(rhs),
+    }
+}
+
+fn div(lhs: Expr, rhs: Expr) -> Expr {
+    Expr::Div {
+        lhs: Box::new(lhs),
+        rhs: Box::new(rhs),
+    }
+}
+
+fn pow(base: Expr, exp: Expr) -> Expr {
+    Expr::Pow {
+        base: Box::new(base),
+        exp: Box::new(exp),
+    }
+}
+
+fn call(name: &str, arg: Expr) -> Expr {
+    Expr::Call {
+        name: name.to_owned(),
+        args: vec![arg],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{error::Error, parser::parse};
+
+    fn derivative(input: &str) -> String {
+        let expr = parse(input).unwrap();
+        expr.derivative("x").unwrap().simplify().to_string()
+    }
+
+    #[test]
+    fn sum_product_and_quotient_rules() {
+        assert_eq!(derivative("x * x"), "2 * x");
+        assert_eq!(derivative("3 * x + 1"), "3");
+        assert_eq!(derivative("x - y"), "1");
+        assert_eq!(derivative("x / y"), "y / y ^ 2");
+    }
+
+    #[test]
+    fn powers_and_functions() {
+        assert_eq!(derivative("x ^ 3"), "3 * x ^ 2");
+        assert_eq!(derivative("sin(x)"), "cos(x)");
+        assert_eq!(derivative("ln(x)"), "1 / x");
+    }
+
+    #[test]
+    fn other_variables_are_constants() {
+        assert_eq!(derivative("3 * y + 5"), "0");
+    }
+
+    #[test]
+    fn some_operations_cannot_be_differentiated() {
+        let err = parse("max(x, 1)").unwrap().derivative("x").unwrap_err();
+        assert!(matches!(err, Error::NotDifferentiable(name) if name == "max"));
+    }
+}
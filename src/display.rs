@@ -0,0 +1,551 @@
+use std::fmt::{self, Display, Formatter};
+
+use crate::parser::Expr;
+
+const PREFIX: usize = 10;
+const POSTFIX: usize = 12;
+
+// Rust prints NaN as `NaN`, which would read back as a variable.
+fn number(num: f64) -> String {
+    if num.is_nan() {
+        "nan".to_owned()
+    } else {
+        num.to_string()
+    }
+}
+
+// Binding strength of each node as the parser sees it. Prefix operators bind
+// tighter than every binary operator but `^`, postfix `!` tighter still.
+fn precedence(expr: &Expr) -> usize {
+    match expr {
+        Expr::Assign { .. } | Expr::Define { .. } => 0,
+        Expr::Cond { .. } => 1,
+        Expr::BitOr { .. } => 2,
+        Expr::BitXor { .. } => 3,
+        Expr::BitAnd { .. } => 4,
+        Expr::Eq { .. } | Expr::Ne { .. } => 5,
+        Expr::Lt { .. } | Expr::Le { .. } | Expr::Gt { .. } | Expr::Ge { .. } => 6,
+        Expr::Shl { .. } | Expr::Shr { .. } => 7,
+        Expr::Add { .. } | Expr::Sub { .. } => 8,
+        Expr::Mul { .. } | Expr::Div { .. } | Expr::FloorDiv { .. } | Expr::Mod { .. } => 9,
+        Expr::Pow { .. } => 11,
+        Expr::Plus(_) | Expr::Minus(_) => PREFIX,
+        Expr::Num(num) if num.is_sign_negative() => PREFIX,
+        Expr::Factorial(_) => POSTFIX,
+        Expr::Num(_) | Expr::Var(_) | Expr::Call { .. } => POSTFIX + 1,
+    }
+}
+
+// How `Expr::pretty` lays an expression out. The default is what `Display`
+// prints: `1 + 2 * 3`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PrettyConfig {
+    // Writes `1 + 2 * 3` and `max(1, 2)` rather than `1+2*3` and `max(1,2)`.
+    pub spaces: bool,
+    // Parenthesizes every operand that is not a number, variable or call, as
+    // in `1 + (2 * 3)`, rather than only those that need it.
+    pub parenthesize_all: bool,
+    pub mul_symbol: MulSymbol,
+}
+
+impl Default for PrettyConfig {
+    fn default() -> Self {
+        PrettyConfig {
+            spaces: true,
+            parenthesize_all: false,
+            mul_symbol: MulSymbol::Star,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MulSymbol {
+    #[default]
+    Star,
+    // `·`, as in `2 · x`. The parser does not read it back.
+    Dot,
+}
+
+impl MulSymbol {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            MulSymbol::Star => "*",
+            MulSymbol::Dot => "·",
+        }
+    }
+}
+
+struct Printer<'a> {
+    out: String,
+    config: &'a PrettyConfig,
+    // Where the operator and the right operand of a tight `%` start, for
+    // `Piece::SpacePercent`.
+    marks: Vec<usize>,
+}
+
+// What is left to print. The printer works through a stack of these rather
+// than recursing, so printing a deep tree cannot overflow the stack.
+enum Piece<'a> {
+    Expr(&'a Expr),
+    // An operand, parenthesized if it binds looser than the level given.
+    Operand(&'a Expr, usize),
+    Text(&'a str),
+    Op(&'a str),
+    Comma,
+    Mark,
+    SpacePercent,
+}
+
+impl<'a> Printer<'a> {
+    fn print(mut self, expr: &Expr) -> String {
+        let mut pending = vec![Piece::Expr(expr)];
+        while let Some(piece) = pending.pop() {
+            match piece {
+                Piece::Expr(expr) => self.expr(expr, &mut pending),
+                Piece::Operand(expr, min_precedence) => {
+                    let precedence = precedence(expr);
+                    if precedence < min_precedence
+                        || self.config.parenthesize_all && precedence <= POSTFIX
+                    {
+                        pending.extend([Piece::Text(")"), Piece::Expr(expr), Piece::Text("(")]);
+                    } else {
+                        pending.push(Piece::Expr(expr));
+                    }
+                }
+                Piece::Text(text) => self.out.push_str(text),
+                Piece::Op(op) => self.op(op),
+                Piece::Comma => self
+                    .out
+                    .push_str(if self.config.spaces { ", " } else { "," }),
+                Piece::Mark => self.marks.push(self.out.len()),
+                // `10%-3` would read as a percent sign, `0.1 - 3`.
+                Piece::SpacePercent => {
+                    let rhs_start = self.marks.pop().expect("marked before the operator");
+                    let op_start = self.marks.pop().expect("marked before the operand");
+                    if self.out[rhs_start..].starts_with(['+', '-']) {
+                        self.out.insert(rhs_start, ' ');
+                        self.out.insert(op_start, ' ');
+                    }
+                }
+            }
+        }
+        self.out
+    }
+
+    // Writes what comes before the node's first operand and queues the rest.
+    fn expr<'e>(&mut self, expr: &'e Expr, pending: &mut Vec<Piece<'e>>) {
+        let pieces = match expr {
+            Expr::Num(num) => {
+                self.out.push_str(&number(*num));
+                return;
+            }
+            Expr::Var(name) => {
+                self.out.push_str(name);
+                return;
+            }
+            Expr::Plus(expr) => {
+                self.out.push('+');
+                vec![Piece::Operand(expr, PREFIX)]
+            }
+            Expr::Minus(expr) => {
+                self.out.push('-');
+                vec![Piece::Operand(expr, PREFIX)]
+            }
+            Expr::Factorial(expr) => vec![Piece::Operand(expr, POSTFIX), Piece::Text("!")],
+            Expr::Call { name, args } if name == "ans" && args.len() == 1 => {
+                self.out.push_str("ans[");
+                vec![Piece::Expr(&args[0]), Piece::Text("]")]
+            }
+            Expr::Call { name, args } => {
+                self.out.push_str(name);
+                self.out.push('(');
+                let mut pieces = Vec::new();
+                for (i, arg) in args.iter().enumerate() {
+                    if i > 0 {
+                        pieces.push(Piece::Comma);
+                    }
+                    pieces.push(Piece::Expr(arg));
+                }
+                pieces.push(Piece::Text(")"));
+                pieces
+            }
+            Expr::Assign { name, value } => {
+                self.out.push_str(name);
+                vec![Piece::Op("="), Piece::Expr(value)]
+            }
+            Expr::Define { name, param, body } => {
+                self.out.push_str(name);
+                self.out.push('(');
+                self.out.push_str(param);
+                self.out.push(')');
+                vec![Piece::Op("="), Piece::Expr(body)]
+            }
+            Expr::Cond {
+                test,
+                then,
+                otherwise,
+            } => vec![
+                Piece::Operand(test, precedence(expr) + 1),
+                Piece::Op("?"),
+                Piece::Expr(then),
+                Piece::Op(":"),
+                Piece::Expr(otherwise),
+            ],
+            _ => {
+                let Some((op, lhs, rhs)) = expr.binary_parts() else {
+                    unreachable!("every other node is binary");
+                };
+                self.binary(lhs, op, rhs, expr)
+            }
+        };
+        pending.extend(pieces.into_iter().rev());
+    }
+
+    fn binary<'e>(&self, lhs: &'e Expr, op: &'e str, rhs: &'e Expr, expr: &Expr) -> Vec<Piece<'e>> {
+        let precedence = precedence(expr);
+        let (lhs_min, rhs_min) = match expr {
+            Expr::Pow { .. } => (precedence + 1, precedence),
+            _ => (precedence, precedence + 1),
+        };
+        let op = match expr {
+            Expr::Mul { .. } => self.config.mul_symbol.as_str(),
+            _ => op,
+        };
+        if !self.config.spaces && op == "%" {
+            return vec![
+                Piece::Operand(lhs, lhs_min),
+                Piece::Mark,
+                Piece::Op(op),
+                Piece::Mark,
+                Piece::Operand(rhs, rhs_min),
+                Piece::SpacePercent,
+            ];
+        }
+        vec![
+            Piece::Operand(lhs, lhs_min),
+            Piece::Op(op),
+            Piece::Operand(rhs, rhs_min),
+        ]
+    }
+
+    fn op(&mut self, op: &str) {
+        // `3!==6` would read as `3 != =6`.
+        let spaced = self.config.spaces || self.out.ends_with('!') && op.starts_with('=');
+        if spaced {
+            self.out.push(' ');
+        }
+        self.out.push_str(op);
+        if spaced {
+            self.out.push(' ');
+        }
+    }
+}
+
+// What is left of an S-expression, as for `Piece`.
+enum Sexpr<'a> {
+    Expr(&'a Expr),
+    Text(&'a str),
+}
+
+impl Display for Expr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.pretty(&PrettyConfig::default()))
+    }
+}
+
+impl Expr {
+    // Prints the expression with as few parentheses as keep its meaning,
+    // unless `config.parenthesize_all` asks for more.
+    pub fn pretty(&self, config: &PrettyConfig) -> String {
+        let printer = Printer {
+            out: String::new(),
+            config,
+            marks: Vec::new(),
+        };
+        printer.print(self)
+    }
+
+    // The operator symbol and operands of a binary node.
+    pub(crate) fn binary_parts(&self) -> Option<(&'static str, &Expr, &Expr)> {
+        Some(match self {
+            Expr::Add { lhs, rhs } => ("+", lhs, rhs),
+            Expr::Sub { lhs, rhs } => ("-", lhs, rhs),
+            Expr::Mul { lhs, rhs } => ("*", lhs, rhs),
+            Expr::Div { lhs, rhs } => ("/", lhs, rhs),
+            Expr::FloorDiv { lhs, rhs } => ("//", lhs, rhs),
+            Expr::Mod { lhs, rhs } => ("%", lhs, rhs),
+            Expr::Pow { base, exp } => ("^", base, exp),
+            Expr::Eq { lhs, rhs } => ("==", lhs, rhs),
+            Expr::Ne { lhs, rhs } => ("!=", lhs, rhs),
+            Expr::Lt { lhs, rhs } => ("<", lhs, rhs),
+            Expr::Le { lhs, rhs } => ("<=", lhs, rhs),
+            Expr::Gt { lhs, rhs } => (">", lhs, rhs),
+            Expr::Ge { lhs, rhs } => (">=", lhs, rhs),
+            Expr::BitAnd { lhs, rhs } => ("&", lhs, rhs),
+            Expr::BitOr { lhs, rhs } => ("|", lhs, rhs),
+            Expr::BitXor { lhs, rhs } => ("^^", lhs, rhs),
+            Expr::Shl { lhs, rhs } => ("<<", lhs, rhs),
+            Expr::Shr { lhs, rhs } => (">>", lhs, rhs),
+            Expr::Num(_)
+            | Expr::Var(_)
+            | Expr::Plus(_)
+            | Expr::Minus(_)
+            | Expr::Factorial(_)
+            | Expr::Call { .. }
+            | Expr::Assign { .. }
+            | Expr::Define { .. }
+            | Expr::Cond { .. } => return None,
+        })
+    }
+
+    // Renders the tree as a Lisp-style S-expression, e.g. `1 + 2 * 3` becomes
+    // `(+ 1 (* 2 3))`.
+    pub fn to_sexpr(&self) -> String {
+        let mut out = String::new();
+        let mut pending = vec![Sexpr::Expr(self)];
+        while let Some(piece) = pending.pop() {
+            let expr = match piece {
+                Sexpr::Expr(expr) => expr,
+                Sexpr::Text(text) => {
+                    out.push_str(text);
+                    continue;
+                }
+            };
+            match expr {
+                Expr::Num(num) => {
+                    out.push_str(&number(*num));
+                    continue;
+                }
+                Expr::Var(name) => {
+                    out.push_str(name);
+                    continue;
+                }
+                Expr::Plus(_) => out.push_str("(pos"),
+                Expr::Minus(_) => out.push_str("(neg"),
+                Expr::Factorial(_) => out.push_str("(!"),
+                Expr::Call { name, .. } => {
+                    out.push('(');
+                    out.push_str(name);
+                }
+                Expr::Assign { name, .. } => {
+                    out.push_str("(= ");
+                    out.push_str(name);
+                }
+                Expr::Define { name, param, .. } => {
+                    out.push_str(&format!("(= ({name} {param})"));
+                }
+                Expr::Cond { .. } => out.push_str("(?"),
+                _ => {
+                    let Some((op, ..)) = expr.binary_parts() else {
+                        unreachable!("every other node is binary");
+                    };
+                    out.push('(');
+                    out.push_str(op);
+                }
+            }
+            pending.push(Sexpr::Text(")"));
+            for child in expr.children().into_iter().rev() {
+                pending.push(Sexpr::Expr(child));
+                pending.push(Sexpr::Text(" "));
+            }
+        }
+        out
+    }
+
+    // Renders the tree in Reverse Polish Notation, one token per entry, so
+    // `1 + 2 * 3` becomes `["1", "2", "3", "*", "+"]`. Signs are `pos` and
+    // `neg`, a call is `name/argc` after its arguments so that variadic calls
+    // stay unambiguous, an assignment is `x value =`, a definition is
+    // `f(x) body =`, and a conditional is `test then otherwise ?`.
+    pub fn to_rpn(&self) -> Vec<String> {
+        let mut tokens = Vec::new();
+        let mut pending = vec![(self, false)];
+        while let Some((expr, expanded)) = pending.pop() {
+            if expanded {
+                tokens.push(expr.rpn_token());
+                continue;
+            }
+            match expr {
+                Expr::Assign { name, .. } => tokens.push(name.clone()),
+                Expr::Define { name, param, .. } => tokens.push(format!("{name}({param})")),
+                _ => {}
+            }
+            pending.push((expr, true));
+            pending.extend(
+                expr.children()
+                    .into_iter()
+                    .rev()
+                    .map(|child| (child, false)),
+            );
+        }
+        tokens
+    }
+
+    fn rpn_token(&self) -> String {
+        match self {
+            Expr::Num(num) => number(*num),
+            Expr::Var(name) => name.clone(),
+            Expr::Plus(_) => "pos".to_owned(),
+            Expr::Minus(_) => "neg".to_owned(),
+            Expr::Factorial(_) => "!".to_owned(),
+            Expr::Call { name, args } => format!("{name}/{}", args.len()),
+            Expr::Assign { .. } | Expr::Define { .. } => "=".to_owned(),
+            Expr::Cond { .. } => "?".to_owned(),
+            _ => {
+                let Some((op, ..)) = self.binary_parts() else {
+                    unreachable!("every other node is binary");
+                };
+                op.to_owned()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+
+    #[test]
+    fn prints_minimal_parentheses() {
+        let expr = Expr::Mul {
+            lhs: Box::new(Expr::Add {
+                lhs: Box::new(Expr::Num(1.0)),
+                rhs: Box::new(Expr::Num(2.0)),
+            }),
+            rhs: Box::new(Expr::Num(3.0)),
+        };
+        assert_eq!(expr.to_string(), "(1 + 2) * 3");
+        assert_eq!(parse("1 + (2 * 3)").unwrap().to_string(), "1 + 2 * 3");
+        assert_eq!(parse("(10 - 2) - 3").unwrap().to_string(), "10 - 2 - 3");
+        assert_eq!(parse("10 - (2 - 3)").unwrap().to_string(), "10 - (2 - 3)");
+        assert_eq!(parse("(2 ^ 3) ^ 2").unwrap().to_string(), "(2 ^ 3) ^ 2");
+    }
+
+    #[test]
+    fn display_round_trips() {
+        let inputs = [
+            "1 + 2 * 3",
+            "(1 + 2) * 3",
+            "2 ^ 3 ^ 2",
+            "-(2 ^ 2)",
+            "(-2) ^ 2",
+            "-x ^ 2 * 3",
+            "2 ^ -x ^ 2",
+            "10 / (5 / 2)",
+            "(3 - 1)!",
+            "max(1, 2 + 3) % 4",
+            "1 < 2 == (3 > 4)",
+            "x = 1 ? 2 : 3",
+        ];
+        for input in inputs {
+            let expr = parse(input).unwrap();
+            let reparsed = parse(&expr.to_string()).unwrap();
+            assert_eq!(reparsed, expr, "{input}");
+        }
+    }
+
+    #[test]
+    fn signs_print_outside_powers() {
+        assert_eq!(parse("-(2 ^ 2)").unwrap().to_string(), "-2 ^ 2");
+        assert_eq!(parse("(-2) ^ 2").unwrap().to_string(), "(-2) ^ 2");
+    }
+
+    #[test]
+    fn sexpr_shows_nesting() {
+        assert_eq!(parse("1 + 2 * 3").unwrap().to_sexpr(), "(+ 1 (* 2 3))");
+        assert_eq!(parse("(1 + 2) * 3").unwrap().to_sexpr(), "(* (+ 1 2) 3)");
+        assert_eq!(parse("max(1, x)").unwrap().to_sexpr(), "(max 1 x)");
+    }
+
+    #[test]
+    fn sexpr_shows_signs() {
+        assert_eq!(parse("-5").unwrap().to_sexpr(), "(neg 5)");
+        assert_eq!(parse("+5").unwrap().to_sexpr(), "(pos 5)");
+        assert_eq!(
+            parse("-(1 - -2)").unwrap().to_sexpr(),
+            "(neg (- 1 (neg 2)))"
+        );
+    }
+
+    fn rpn(input: &str) -> Vec<String> {
+        parse(input).unwrap().to_rpn()
+    }
+
+    #[test]
+    fn rpn_follows_precedence() {
+        assert_eq!(rpn("1 + 2 * 3"), ["1", "2", "3", "*", "+"]);
+        assert_eq!(rpn("(1 + 2) * 3"), ["1", "2", "+", "3", "*"]);
+        assert_eq!(rpn("10 - 2 - 3"), ["10", "2", "-", "3", "-"]);
+        assert_eq!(rpn("2 ^ 3 ^ 2"), ["2", "3", "2", "^", "^"]);
+        assert_eq!(rpn("max(1, x, 3)"), ["1", "x", "3", "max/3"]);
+    }
+
+    #[test]
+    fn rpn_spells_signs_out() {
+        assert_eq!(rpn("-x"), ["x", "neg"]);
+        assert_eq!(rpn("+x"), ["x", "pos"]);
+        assert_eq!(rpn("-(1 - 2)"), ["1", "2", "-", "neg"]);
+        assert_eq!(rpn("3!"), ["3", "!"]);
+    }
+
+    #[test]
+    fn rpn_marks_statements() {
+        assert_eq!(rpn("x = 1 + 2"), ["x", "1", "2", "+", "="]);
+        assert_eq!(rpn("f(x) = x * 2"), ["f(x)", "x", "2", "*", "="]);
+        assert_eq!(rpn("x ? 1 : 2"), ["x", "1", "2", "?"]);
+    }
+
+    fn pretty(input: &str, spaces: bool, parenthesize_all: bool, mul_symbol: MulSymbol) -> String {
+        let config = PrettyConfig {
+            spaces,
+            parenthesize_all,
+            mul_symbol,
+        };
+        parse(input).unwrap().pretty(&config)
+    }
+
+    #[test]
+    fn pretty_spacing() {
+        let expr = parse("1+2*3").unwrap();
+        assert_eq!(expr.pretty(&PrettyConfig::default()), "1 + 2 * 3");
+        assert_eq!(pretty("1 + 2 * 3", false, false, MulSymbol::Star), "1+2*3");
+        assert_eq!(
+            pretty("max(1, 2)", false, false, MulSymbol::Star),
+            "max(1,2)"
+        );
+        assert_eq!(pretty("10 % -3", false, false, MulSymbol::Star), "10 % -3");
+        assert_eq!(pretty("3! == 6", false, false, MulSymbol::Star), "3! == 6");
+    }
+
+    #[test]
+    fn pretty_parenthesizes_everything() {
+        let star = MulSymbol::Star;
+        assert_eq!(pretty("1 + 2 * 3", true, true, star), "1 + (2 * 3)");
+        assert_eq!(pretty("1 + 2 * 3", false, true, star), "1+(2*3)");
+        assert_eq!(
+            pretty("(-x) ^ 2 + f(y)", true, true, star),
+            "((-x) ^ 2) + f(y)"
+        );
+        assert_eq!(pretty("-x ^ 2", true, true, star), "-(x ^ 2)");
+    }
+
+    #[test]
+    fn pretty_multiplication_symbol() {
+        assert_eq!(
+            pretty("2 * x * y", true, false, MulSymbol::Dot),
+            "2 · x · y"
+        );
+        assert_eq!(pretty("2x", false, false, MulSymbol::Dot), "2·x");
+    }
+
+    // Tight output still has to mean the same thing.
+    #[test]
+    fn tight_output_round_trips() {
+        for input in ["1 - -2", "10 % -3", "3! == 6", "x = 1 ? 2 : -3", "2 ^ -x"] {
+            let expr = parse(input).unwrap();
+            let tight = pretty(input, false, false, MulSymbol::Star);
+            assert_eq!(parse(&tight).unwrap(), expr, "{input} -> {tight}");
+        }
+    }
+}
@@ -1,17 +1,189 @@
+use std::fmt::{self, Display, Formatter};
+
 use thiserror::Error;
 
+use crate::parser::Span;
+
 pub type Result<T> = std::result::Result<T, Error>;
 
 #[derive(Error, Debug)]
 pub enum Error {
     #[error("Invalid binary operation")]
-    InvalidBinOp(),
+    InvalidBinOp(Span),
+    #[error("Missing operator")]
+    MissingOperator(Span),
     #[error("Invalid unary operation")]
-    InvalidUnaryOp(),
+    InvalidUnaryOp(Span),
+    #[error("Unexpected end of input, expected an operand")]
+    UnexpectedEof(Span),
     #[error("Invalid identifier")]
-    InvalidIdent(),
+    InvalidIdent(Span),
     #[error("Invalid number: {0:?}")]
-    InvalidNumber(String),
+    InvalidNumber(String, Span),
+    #[error("Malformed number: {0:?}")]
+    MalformedNumber(String, Span),
+    #[error("Unclosed parenthesis")]
+    UnclosedParen(Span),
+    #[error("Unexpected closing parenthesis")]
+    UnexpectedRightParen(Span),
+    #[error("Unclosed absolute value bar")]
+    UnclosedBar(Span),
+    #[error("Expression nested too deeply")]
+    ExpressionTooDeep(Span),
+    #[error("Not enough operands for RPN operator")]
+    RpnUnderflow(Span),
+    #[error("Extra RPN operands: {0} left over")]
+    RpnExtraOperands(usize),
+    #[error("Invalid RPN token")]
+    InvalidRpnToken(Span),
     #[error("Unexpected character: {0:?}")]
-    UnexpectedChar(char),
+    UnexpectedChar(char, Span),
+    #[error("Empty expression")]
+    EmptyExpression(),
+    #[error("Unknown function: {0:?}")]
+    UnknownFunction(String),
+    #[error("Wrong number of arguments for {0:?}")]
+    WrongArgCount(String),
+    #[error("Argument out of domain for {0:?}")]
+    OutOfDomain(String),
+    #[error("Undefined variable: {0:?}")]
+    UndefinedVariable(String),
+    #[error("Recursion limit reached in {0:?}")]
+    RecursionLimit(String),
+    #[error("No such result: [{0}]")]
+    NoSuchResult(f64),
+    #[error("Non-integer operand: {0}")]
+    NonIntegerOperand(f64),
+    #[error("Complex operand to a real-only operation")]
+    ComplexOperand(),
+    #[error("Incompatible units")]
+    UnitMismatch(),
+    #[error("Division by zero")]
+    DivisionByZero(),
+    #[error("Factorial of negative number: {0}")]
+    NegativeFactorial(f64),
+    #[error("Result is not a number")]
+    NotANumber(),
+    #[error("Arithmetic overflow")]
+    Overflow(),
+    #[error("Cannot differentiate {0:?}")]
+    NotDifferentiable(String),
+}
+
+impl Error {
+    // Where in the input the error is, for errors found while parsing.
+    pub fn span(&self) -> Option<Span> {
+        match *self {
+            Error::InvalidBinOp(span)
+            | Error::MissingOperator(span)
+            | Error::InvalidUnaryOp(span)
+            | Error::UnexpectedEof(span)
+            | Error::InvalidIdent(span)
+            | Error::InvalidNumber(_, span)
+            | Error::MalformedNumber(_, span)
+            | Error::UnclosedParen(span)
+            | Error::UnexpectedRightParen(span)
+            | Error::UnclosedBar(span)
+            | Error::ExpressionTooDeep(span)
+            | Error::RpnUnderflow(span)
+            | Error::InvalidRpnToken(span)
+            | Error::UnexpectedChar(_, span) => Some(span),
+            _ => None,
+        }
+    }
+}
+
+// The error message followed, when the error has a span, by the line of
+// `input` it is on and a `^` under each of its characters:
+//
+// ```text
+// Invalid unary operation
+// 1 + * 2
+//     ^
+// ```
+//
+// An empty span, as at the end of the input, gets a single `^`.
+pub fn render_error(input: &str, err: &Error) -> String {
+    let Some(span) = err.span() else {
+        return err.to_string();
+    };
+    let start = span.start.min(input.len());
+    let end = span.end.clamp(start, input.len());
+    let line_start = input[..start].rfind('\n').map_or(0, |i| i + 1);
+    let line_end = input[start..].find('\n').map_or(input.len(), |i| start + i);
+    let indent = input[line_start..start].chars().count();
+    let width = input[start..end.min(line_end)].chars().count().max(1);
+    format!(
+        "{err}\n{}\n{}{}",
+        &input[line_start..line_end],
+        " ".repeat(indent),
+        "^".repeat(width)
+    )
+}
+
+// A non-fatal note from `parser::parse_with_diagnostics` on how the input was
+// read.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Diagnostic {
+    // A `*` was inserted before the operand at the span, as in `2pi` or
+    // `2(3)`.
+    ImplicitMul(Span),
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Diagnostic::ImplicitMul(_) => write!(
+                f,
+                "Implicit multiplication: the operand here is multiplied by the one before it"
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{eval_str, parser::parse};
+
+    fn render(input: &str) -> String {
+        render_error(input, &parse(input).unwrap_err())
+    }
+
+    #[test]
+    fn carets_sit_under_the_span() {
+        assert_eq!(render("1 + * 2"), "Invalid unary operation\n1 + * 2\n    ^");
+        assert_eq!(render("(1 + 2"), "Unclosed parenthesis\n(1 + 2\n^");
+        assert_eq!(
+            render("2 + 0xzz"),
+            "Invalid number: \"0xzz\"\n2 + 0xzz\n    ^^^^"
+        );
+    }
+
+    #[test]
+    fn the_end_of_the_input_gets_one_caret() {
+        assert_eq!(
+            render("1 +"),
+            "Unexpected end of input, expected an operand\n1 +\n   ^"
+        );
+    }
+
+    #[test]
+    fn carets_count_characters_not_bytes() {
+        assert_eq!(
+            render("√√ + $"),
+            "Unexpected character: '$'\n√√ + $\n     ^"
+        );
+    }
+
+    #[test]
+    fn only_the_line_with_the_error_is_shown() {
+        assert_eq!(render("1\n+ * 2"), "Invalid unary operation\n+ * 2\n  ^");
+    }
+
+    #[test]
+    fn errors_without_a_span_are_just_the_message() {
+        let err = eval_str("1 / 0").unwrap_err();
+        assert_eq!(render_error("1 / 0", &err), "Division by zero");
+    }
 }
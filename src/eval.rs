@@ -0,0 +1,1594 @@
+use std::{collections::HashMap, sync::Arc};
+
+use crate::{
+    error::{Error, Result},
+    parser::Expr,
+};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AngleMode {
+    #[default]
+    Radians,
+    Degrees,
+}
+
+// The character that starts the fraction of a number literal. With `Comma`,
+// `3,14` is 3.14 and call arguments are separated by `;` instead, as in
+// `max(1,5; 2)`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DecimalSeparator {
+    #[default]
+    Dot,
+    Comma,
+}
+
+impl DecimalSeparator {
+    pub fn as_char(self) -> char {
+        match self {
+            DecimalSeparator::Dot => '.',
+            DecimalSeparator::Comma => ',',
+        }
+    }
+}
+
+// What `eval_value` does when an `Int` addition, subtraction,
+// multiplication, `^` or negation overflows `i64`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum IntOverflow {
+    // Redo the operation in floating point.
+    #[default]
+    Float,
+    // Clamp to `i64::MIN` or `i64::MAX`.
+    Saturate,
+    // Wrap around in two's complement.
+    Wrap,
+    // Fail with `Error::Overflow`.
+    Error,
+}
+
+#[derive(Clone, Debug)]
+pub struct EvalConfig {
+    pub angle_mode: AngleMode,
+    // Read by `parser::parse_with_config`; evaluation itself ignores it.
+    pub decimal_separator: DecimalSeparator,
+    // Report `Error::NotANumber` as soon as any operation yields NaN, such
+    // as `0 * inf` or `inf - inf`, instead of letting it propagate.
+    pub error_on_nan: bool,
+    // How many levels `eval_in` descends by plain recursion, which is faster
+    // for the shallow trees typed into the REPL, before handing a deeper
+    // subtree to the explicit stack. 0 always uses the explicit stack.
+    pub recursion_limit: usize,
+    pub int_overflow: IntOverflow,
+}
+
+impl Default for EvalConfig {
+    fn default() -> Self {
+        EvalConfig {
+            angle_mode: AngleMode::Radians,
+            decimal_separator: DecimalSeparator::Dot,
+            error_on_nan: false,
+            recursion_limit: 64,
+            int_overflow: IntOverflow::Float,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Environment {
+    vars: HashMap<String, f64>,
+    functions: HashMap<String, Arc<Function>>,
+    // How many calls to defined functions are under way.
+    calls: usize,
+    // Earlier results, read by `ans[n]` where `n` counts from 1.
+    results: Vec<f64>,
+    pub config: EvalConfig,
+}
+
+// A function defined with `name(param) = body`.
+#[derive(Debug)]
+struct Function {
+    param: String,
+    body: Expr,
+}
+
+// How deeply calls to defined functions may nest, which is what stops
+// `f(x) = f(x)`.
+const MAX_CALLS: usize = 256;
+
+impl Environment {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_config(config: EvalConfig) -> Self {
+        Environment {
+            vars: HashMap::new(),
+            functions: HashMap::new(),
+            calls: 0,
+            results: Vec::new(),
+            config,
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<f64> {
+        self.vars.get(name).copied()
+    }
+
+    pub fn set(&mut self, name: impl Into<String>, value: f64) {
+        self.vars.insert(name.into(), value);
+    }
+
+    pub fn vars(&self) -> impl Iterator<Item = (&str, f64)> {
+        self.vars
+            .iter()
+            .map(|(name, &value)| (name.as_str(), value))
+    }
+
+    // Forgets the variables and functions but keeps the results, so `ans[n]`
+    // still refers to the `n`th one.
+    pub fn clear(&mut self) {
+        self.vars.clear();
+        self.functions.clear();
+    }
+
+    // Replaces any earlier function of the same name.
+    pub fn define(&mut self, name: impl Into<String>, param: impl Into<String>, body: Expr) {
+        let function = Function {
+            param: param.into(),
+            body,
+        };
+        self.functions.insert(name.into(), Arc::new(function));
+    }
+
+    pub fn push_result(&mut self, value: f64) {
+        self.results.push(value);
+    }
+
+    pub fn result(&self, n: usize) -> Option<f64> {
+        self.results.get(n.checked_sub(1)?).copied()
+    }
+}
+
+pub(crate) enum Frame<'a> {
+    Eval(&'a Expr),
+    Apply(&'a Expr),
+    Branch { then: &'a Expr, otherwise: &'a Expr },
+}
+
+impl Expr {
+    pub fn eval(&self) -> Result<f64> {
+        self.eval_in(&mut Environment::new())
+    }
+
+    pub fn eval_with_config(&self, config: &EvalConfig) -> Result<f64> {
+        self.eval_in(&mut Environment::with_config(config.clone()))
+    }
+
+    // Like `eval`, but an operation on finite operands that yields inf or
+    // NaN, as in `1e308 * 10`, is reported as `Error::Overflow`. The literal
+    // `inf` and infinities stored in variables still evaluate.
+    pub fn eval_checked(&self) -> Result<f64> {
+        self.eval_checked_in(&mut Environment::new())
+    }
+
+    // The check also covers the bodies of the defined functions called.
+    pub fn eval_checked_in(&self, env: &mut Environment) -> Result<f64> {
+        self.walk_observed(env, &mut check)
+    }
+
+    // Recurses for the first `config.recursion_limit` levels and walks
+    // anything deeper with an explicit stack, so that arbitrarily deep
+    // expressions cannot overflow the call stack.
+    //
+    // Evaluation order is guaranteed: operands and function arguments are
+    // evaluated left to right, and only the taken branch of a conditional is
+    // evaluated, so in `(x = 2) * x` the assignment happens before `x` is
+    // read. `eval_recording` makes the order observable.
+    pub fn eval_in(&self, env: &mut Environment) -> Result<f64> {
+        let mut values = Vec::new();
+        self.eval_nested(env, &mut values, env.config.recursion_limit)?;
+        Ok(pop(&mut values))
+    }
+
+    // Evaluates in the same order as `walk`, pushing the value onto `values`,
+    // but recursing on the call stack until `depth` runs out.
+    fn eval_nested(
+        &self,
+        env: &mut Environment,
+        values: &mut Vec<f64>,
+        depth: usize,
+    ) -> Result<()> {
+        let Some(depth) = depth.checked_sub(1) else {
+            let value = self.walk(env, apply, |&value| value != 0.0)?;
+            values.push(value);
+            return Ok(());
+        };
+        match self {
+            Expr::Num(_) | Expr::Var(_) | Expr::Define { .. } => {}
+            Expr::Plus(expr)
+            | Expr::Minus(expr)
+            | Expr::Factorial(expr)
+            | Expr::Assign { value: expr, .. } => expr.eval_nested(env, values, depth)?,
+            Expr::Add { lhs, rhs }
+            | Expr::Sub { lhs, rhs }
+            | Expr::Mul { lhs, rhs }
+            | Expr::Div { lhs, rhs }
+            | Expr::FloorDiv { lhs, rhs }
+            | Expr::Mod { lhs, rhs }
+            | Expr::Eq { lhs, rhs }
+            | Expr::Ne { lhs, rhs }
+            | Expr::Lt { lhs, rhs }
+            | Expr::Le { lhs, rhs }
+            | Expr::Gt { lhs, rhs }
+            | Expr::Ge { lhs, rhs }
+            | Expr::BitAnd { lhs, rhs }
+            | Expr::BitOr { lhs, rhs }
+            | Expr::BitXor { lhs, rhs }
+            | Expr::Shl { lhs, rhs }
+            | Expr::Shr { lhs, rhs }
+            | Expr::Pow {
+                base: lhs,
+                exp: rhs,
+            } => {
+                lhs.eval_nested(env, values, depth)?;
+                rhs.eval_nested(env, values, depth)?;
+            }
+            Expr::Call { args, .. } => {
+                for arg in args {
+                    arg.eval_nested(env, values, depth)?;
+                }
+            }
+            Expr::Cond {
+                test,
+                then,
+                otherwise,
+            } => {
+                test.eval_nested(env, values, depth)?;
+                let branch = if pop(values) != 0.0 { then } else { otherwise };
+                branch.eval_nested(env, values, depth)?;
+            }
+        }
+        let value = apply(self, values, env)?;
+        values.push(value);
+        Ok(())
+    }
+
+    // Looks variables up through `resolver` instead of an `Environment`.
+    // Variables assigned within the expression shadow the resolver.
+    pub fn eval_with(&self, resolver: impl Fn(&str) -> Option<f64>) -> Result<f64> {
+        let resolve = |expr: &Expr, values: &mut Vec<f64>, env: &mut Environment| match expr {
+            Expr::Var(name) if env.get(name).is_none() => {
+                resolver(name).ok_or_else(|| Error::UndefinedVariable(name.clone()))
+            }
+            _ => apply(expr, values, env),
+        };
+        self.walk(&mut Environment::new(), resolve, |&value| value != 0.0)
+    }
+
+    // Evaluates like `eval_in`, appending the value of every number and
+    // variable to `leaves` as it is read, including those in the bodies of
+    // defined functions.
+    pub fn eval_recording(&self, env: &mut Environment, leaves: &mut Vec<f64>) -> Result<f64> {
+        let mut record = |expr: &Expr, _: &[f64], value: f64| {
+            if let Expr::Num(_) | Expr::Var(_) = expr {
+                leaves.push(value);
+            }
+            Ok(())
+        };
+        self.walk_observed(env, &mut record)
+    }
+
+    // Evaluates like `eval`, also returning every operation in the order it
+    // was carried out, so `(1 + 2) * 4` gives `["1 + 2 = 3", "3 * 4 = 12"]`.
+    // Negative operands are parenthesized; numbers, variables, signs,
+    // conditionals and assignments are not steps of their own.
+    pub fn eval_trace(&self) -> Result<(f64, Vec<String>)> {
+        self.eval_trace_in(&mut Environment::new())
+    }
+
+    // A call to a defined function lists the steps of its body before the
+    // call itself, as in `["2 * 3 = 6", "f(3) = 6"]`.
+    pub fn eval_trace_in(&self, env: &mut Environment) -> Result<(f64, Vec<String>)> {
+        let mut steps = Vec::new();
+        let mut trace = |expr: &Expr, operands: &[f64], value: f64| {
+            if let Some(step) = step(expr, operands) {
+                steps.push(format!("{step} = {value}"));
+            }
+            Ok(())
+        };
+        let value = self.walk_observed(env, &mut trace)?;
+        Ok((value, steps))
+    }
+
+    fn walk_observed(&self, env: &mut Environment, observe: &mut Observer) -> Result<f64> {
+        let apply = |expr: &Expr, values: &mut Vec<f64>, env: &mut Environment| {
+            apply_observed(expr, values, env, Some(&mut *observe))
+        };
+        self.walk(env, apply, |&value| value != 0.0)
+    }
+
+    // The traversal shared by the evaluators: `apply` combines the values of
+    // a node's operands, `truthy` decides which branch of a conditional is
+    // taken.
+    pub(crate) fn walk<T>(
+        &self,
+        env: &mut Environment,
+        mut apply: impl FnMut(&Expr, &mut Vec<T>, &mut Environment) -> Result<T>,
+        truthy: impl Fn(&T) -> bool,
+    ) -> Result<T> {
+        let mut frames = vec![Frame::Eval(self)];
+        let mut values = Vec::new();
+        while let Some(frame) = frames.pop() {
+            match frame {
+                Frame::Eval(expr) => {
+                    frames.push(Frame::Apply(expr));
+                    match expr {
+                        // A definition's body is only evaluated when called.
+                        Expr::Num(_) | Expr::Var(_) | Expr::Define { .. } => {}
+                        Expr::Plus(expr)
+                        | Expr::Minus(expr)
+                        | Expr::Factorial(expr)
+                        | Expr::Assign { value: expr, .. } => {
+                            frames.push(Frame::Eval(expr));
+                        }
+                        Expr::Add { lhs, rhs }
+                        | Expr::Sub { lhs, rhs }
+                        | Expr::Mul { lhs, rhs }
+                        | Expr::Div { lhs, rhs }
+                        | Expr::FloorDiv { lhs, rhs }
+                        | Expr::Mod { lhs, rhs }
+                        | Expr::Eq { lhs, rhs }
+                        | Expr::Ne { lhs, rhs }
+                        | Expr::Lt { lhs, rhs }
+                        | Expr::Le { lhs, rhs }
+                        | Expr::Gt { lhs, rhs }
+                        | Expr::Ge { lhs, rhs }
+                        | Expr::BitAnd { lhs, rhs }
+                        | Expr::BitOr { lhs, rhs }
+                        | Expr::BitXor { lhs, rhs }
+                        | Expr::Shl { lhs, rhs }
+                        | Expr::Shr { lhs, rhs }
+                        | Expr::Pow {
+                            base: lhs,
+                            exp: rhs,
+                        } => {
+                            frames.push(Frame::Eval(rhs));
+                            frames.push(Frame::Eval(lhs));
+                        }
+                        Expr::Call { args, .. } => {
+                            frames.extend(args.iter().rev().map(Frame::Eval));
+                        }
+                        Expr::Cond {
+                            test,
+                            then,
+                            otherwise,
+                        } => {
+                            frames.push(Frame::Branch { then, otherwise });
+                            frames.push(Frame::Eval(test));
+                        }
+                    }
+                }
+                // Only the taken branch of a conditional is evaluated; any
+                // nonzero test value counts as true.
+                Frame::Branch { then, otherwise } => {
+                    if truthy(&values.pop().unwrap()) {
+                        frames.push(Frame::Eval(then));
+                    } else {
+                        frames.push(Frame::Eval(otherwise));
+                    }
+                }
+                Frame::Apply(expr) => {
+                    let value = apply(expr, &mut values, env)?;
+                    values.push(value);
+                }
+            }
+        }
+        Ok(values.pop().unwrap())
+    }
+}
+
+// Called after every operation, in the body of a defined function too, with
+// the node, the values of its operands and its own value. An error stops the
+// evaluation.
+type Observer<'a> = dyn FnMut(&Expr, &[f64], f64) -> Result<()> + 'a;
+
+pub(crate) fn apply(expr: &Expr, values: &mut Vec<f64>, env: &mut Environment) -> Result<f64> {
+    apply_observed(expr, values, env, None)
+}
+
+fn apply_observed(
+    expr: &Expr,
+    values: &mut Vec<f64>,
+    env: &mut Environment,
+    mut observe: Option<&mut Observer>,
+) -> Result<f64> {
+    let operands = observe
+        .is_some()
+        .then(|| values[values.len() - arity(expr)..].to_vec());
+    let value = apply_op(expr, values, env, observe.as_deref_mut())?;
+    if value.is_nan() && env.config.error_on_nan {
+        return Err(Error::NotANumber());
+    }
+    if let (Some(observe), Some(operands)) = (observe, operands) {
+        observe(expr, &operands, value)?;
+    }
+    Ok(value)
+}
+
+// The operation `expr` carries out on `operands`, written out for
+// `eval_trace`.
+fn step(expr: &Expr, operands: &[f64]) -> Option<String> {
+    let operand = |value: f64| {
+        if value.is_sign_negative() && value != 0.0 {
+            format!("({value})")
+        } else {
+            value.to_string()
+        }
+    };
+    Some(match (expr, operands) {
+        (Expr::Factorial(_), &[x]) => format!("{}!", operand(x)),
+        (Expr::Call { name, .. }, args) => {
+            let args: Vec<String> = args.iter().map(f64::to_string).collect();
+            format!("{name}({})", args.join(", "))
+        }
+        (_, &[lhs, rhs]) => {
+            let (op, ..) = expr.binary_parts()?;
+            format!("{} {op} {}", operand(lhs), operand(rhs))
+        }
+        _ => return None,
+    })
+}
+
+fn check(expr: &Expr, operands: &[f64], value: f64) -> Result<()> {
+    let finite = !matches!(expr, Expr::Num(_) | Expr::Var(_))
+        && operands.iter().all(|value| value.is_finite());
+    if finite && !value.is_finite() {
+        return Err(Error::Overflow());
+    }
+    Ok(())
+}
+
+// How many values the node takes off the stack when it is applied.
+pub(crate) fn arity(expr: &Expr) -> usize {
+    match expr {
+        Expr::Num(_) | Expr::Var(_) | Expr::Define { .. } => 0,
+        Expr::Plus(_)
+        | Expr::Minus(_)
+        | Expr::Factorial(_)
+        | Expr::Assign { .. }
+        | Expr::Cond { .. } => 1,
+        Expr::Call { args, .. } => args.len(),
+        _ => 2,
+    }
+}
+
+fn apply_op(
+    expr: &Expr,
+    values: &mut Vec<f64>,
+    env: &mut Environment,
+    observe: Option<&mut Observer>,
+) -> Result<f64> {
+    Ok(match expr {
+        Expr::Num(num) => *num,
+        Expr::Var(name) => env
+            .get(name)
+            .ok_or_else(|| Error::UndefinedVariable(name.clone()))?,
+        Expr::Plus(_) | Expr::Cond { .. } => pop(values),
+        Expr::Minus(_) => -pop(values),
+        Expr::Factorial(_) => factorial(pop(values))?,
+        Expr::Add { .. } => binary(values, |lhs, rhs| lhs + rhs),
+        Expr::Sub { .. } => binary(values, |lhs, rhs| lhs - rhs),
+        Expr::Mul { .. } => binary(values, |lhs, rhs| lhs * rhs),
+        Expr::Div { .. } => {
+            let (lhs, rhs) = pop_pair(values);
+            lhs / divisor(rhs)?
+        }
+        // Rounds toward negative infinity, so `-7 // 2` is `-4`.
+        Expr::FloorDiv { .. } => {
+            let (lhs, rhs) = pop_pair(values);
+            (lhs / divisor(rhs)?).floor()
+        }
+        // Euclidean remainder: never negative for a positive divisor, so
+        // `-7 % 3` is `2`.
+        Expr::Mod { .. } => {
+            let (lhs, rhs) = pop_pair(values);
+            lhs.rem_euclid(divisor(rhs)?)
+        }
+        Expr::Pow { .. } => binary(values, f64::powf),
+        // Comparisons yield 1 for true and 0 for false. Equality is exact, so
+        // `0.1 + 0.2 == 0.3` is 0 like it is for any f64 comparison.
+        Expr::Eq { .. } => binary(values, |lhs, rhs| truth(lhs == rhs)),
+        Expr::Ne { .. } => binary(values, |lhs, rhs| truth(lhs != rhs)),
+        Expr::Lt { .. } => binary(values, |lhs, rhs| truth(lhs < rhs)),
+        Expr::Le { .. } => binary(values, |lhs, rhs| truth(lhs <= rhs)),
+        Expr::Gt { .. } => binary(values, |lhs, rhs| truth(lhs > rhs)),
+        Expr::Ge { .. } => binary(values, |lhs, rhs| truth(lhs >= rhs)),
+        // Bitwise operators work on the two's complement `i64` value of
+        // integral operands.
+        Expr::BitAnd { .. } => bitwise(values, |lhs, rhs| Some(lhs & rhs), "&")?,
+        Expr::BitOr { .. } => bitwise(values, |lhs, rhs| Some(lhs | rhs), "|")?,
+        Expr::BitXor { .. } => bitwise(values, |lhs, rhs| Some(lhs ^ rhs), "^^")?,
+        Expr::Shl { .. } => bitwise(values, |lhs, rhs| lhs.checked_shl(shift(rhs)?), "<<")?,
+        Expr::Shr { .. } => bitwise(values, |lhs, rhs| lhs.checked_shr(shift(rhs)?), ">>")?,
+        Expr::Call { name, args } => {
+            let args = values.split_off(values.len() - args.len());
+            match (name.as_str(), args.as_slice()) {
+                ("ans", &[n]) => previous_result(env, n)?,
+                _ => match env.functions.get(name).cloned() {
+                    Some(function) => call_defined(&function, name, &args, env, observe)?,
+                    None => call(name, &args, &env.config)?,
+                },
+            }
+        }
+        Expr::Assign { name, .. } => {
+            let value = pop(values);
+            env.set(name.clone(), value);
+            value
+        }
+        // A definition has no value of its own and evaluates to 0.
+        Expr::Define { name, param, body } => {
+            env.define(name.clone(), param.clone(), (**body).clone());
+            0.0
+        }
+    })
+}
+
+// The parameter is bound for the duration of the call, shadowing any
+// variable of the same name; every other variable the body reads or assigns
+// is the caller's.
+fn call_defined(
+    function: &Function,
+    name: &str,
+    args: &[f64],
+    env: &mut Environment,
+    mut observe: Option<&mut Observer>,
+) -> Result<f64> {
+    let &[arg] = args else {
+        return Err(Error::WrongArgCount(name.to_owned()));
+    };
+    if env.calls >= MAX_CALLS {
+        return Err(Error::RecursionLimit(name.to_owned()));
+    }
+    let outer = env.vars.insert(function.param.clone(), arg);
+    env.calls += 1;
+    let apply = |expr: &Expr, values: &mut Vec<f64>, env: &mut Environment| {
+        apply_observed(expr, values, env, observe.as_deref_mut())
+    };
+    let value = function.body.walk(env, apply, |&value| value != 0.0);
+    env.calls -= 1;
+    match outer {
+        Some(outer) => env.vars.insert(function.param.clone(), outer),
+        None => env.vars.remove(&function.param),
+    };
+    value
+}
+
+// `ans[n]`, which the parser reads as a call to `ans`. An integral index out
+// of range, however large, is no such result rather than a bad operand.
+fn previous_result(env: &Environment, n: f64) -> Result<f64> {
+    if n.fract() != 0.0 {
+        return Err(Error::NonIntegerOperand(n));
+    }
+    // `as` saturates, and no result has an index of 0 or `usize::MAX`.
+    env.result(n as usize).ok_or(Error::NoSuchResult(n))
+}
+
+fn pop(values: &mut Vec<f64>) -> f64 {
+    values.pop().unwrap()
+}
+
+fn pop_pair(values: &mut Vec<f64>) -> (f64, f64) {
+    let rhs = pop(values);
+    let lhs = pop(values);
+    (lhs, rhs)
+}
+
+fn binary(values: &mut Vec<f64>, op: impl Fn(f64, f64) -> f64) -> f64 {
+    let (lhs, rhs) = pop_pair(values);
+    op(lhs, rhs)
+}
+
+// `op` returning `None` means the operand values are outside what the
+// operator accepts, such as a shift by 64 or more bits.
+fn bitwise(
+    values: &mut Vec<f64>,
+    op: impl Fn(i64, i64) -> Option<i64>,
+    symbol: &str,
+) -> Result<f64> {
+    let (lhs, rhs) = pop_pair(values);
+    let value = op(integer(lhs)?, integer(rhs)?);
+    value
+        .map(|value| value as f64)
+        .ok_or_else(|| Error::OutOfDomain(symbol.to_owned()))
+}
+
+fn integer(value: f64) -> Result<i64> {
+    if value.fract() != 0.0 || value < i64::MIN as f64 || value >= -(i64::MIN as f64) {
+        return Err(Error::NonIntegerOperand(value));
+    }
+    Ok(value as i64)
+}
+
+pub(crate) fn shift(amount: i64) -> Option<u32> {
+    u32::try_from(amount).ok()
+}
+
+fn truth(value: bool) -> f64 {
+    if value {
+        1.0
+    } else {
+        0.0
+    }
+}
+
+pub(crate) fn divisor(value: f64) -> Result<f64> {
+    if value == 0.0 {
+        return Err(Error::DivisionByZero());
+    }
+    Ok(value)
+}
+
+pub(crate) const FUNCTIONS: &[&str] = &[
+    "sqrt", "abs", "floor", "ceil", "trunc", "fract", "sin", "cos", "tan", "deg", "rad", "ln",
+    "log", "max", "min", "round", "pct", "compound", "avg", "clamp", "sign", "pow", "exp", "log2",
+    "log10", "approx", "gcd", "lcm",
+];
+
+// The tolerance of `approx(a, b)` without a third argument.
+const APPROX_EPSILON: f64 = 1e-9;
+
+fn call(name: &str, args: &[f64], config: &EvalConfig) -> Result<f64> {
+    match (name, args) {
+        ("sqrt", [x]) if *x >= 0.0 => Ok(x.sqrt()),
+        ("abs", [x]) => Ok(x.abs()),
+        // `floor` and `ceil` round down and up, `trunc` toward zero, and
+        // `fract` is the part `trunc` drops, which keeps the sign of `x`.
+        ("floor", [x]) => Ok(x.floor()),
+        ("ceil", [x]) => Ok(x.ceil()),
+        ("trunc", [x]) => Ok(x.trunc()),
+        ("fract", [x]) => Ok(x.fract()),
+        // Angles are read according to the configured angle mode; `deg` and
+        // `rad` always convert radians to degrees and back.
+        ("sin", [x]) => Ok(angle(*x, config).sin()),
+        ("cos", [x]) => Ok(angle(*x, config).cos()),
+        ("tan", [x]) => Ok(angle(*x, config).tan()),
+        ("deg", [x]) => Ok(x.to_degrees()),
+        ("rad", [x]) => Ok(x.to_radians()),
+        ("ln", [x]) if *x > 0.0 => Ok(x.ln()),
+        ("log", [base, x]) if *base > 0.0 && *base != 1.0 && *x > 0.0 => Ok(log(*base, *x)),
+        ("log2", [x]) if *x > 0.0 => Ok(x.log2()),
+        ("log10", [x]) if *x > 0.0 => Ok(x.log10()),
+        // `pow(base, exp)` is `base ^ exp` and `exp(x)` is `e ^ x`.
+        ("pow", [base, exp]) => Ok(base.powf(*exp)),
+        ("exp", [x]) => Ok(x.exp()),
+        // Variadic, taking at least one argument. NaN arguments are skipped
+        // as `f64::max` and `f64::min` do.
+        ("max", [first, rest @ ..]) => Ok(rest.iter().fold(*first, |max, &x| max.max(x))),
+        ("min", [first, rest @ ..]) => Ok(rest.iter().fold(*first, |min, &x| min.min(x))),
+        // `pct(x)` is x percent as a fraction, `compound(principal, rate,
+        // periods)` the value after compounding `rate` per period, and
+        // `avg(a, ...)` the mean of one or more arguments.
+        ("pct", [x]) => Ok(x / 100.0),
+        ("compound", [principal, rate, periods]) => Ok(principal * (1.0 + rate).powf(*periods)),
+        ("avg", [_, ..]) => Ok(args.iter().sum::<f64>() / args.len() as f64),
+        // `clamp(x, lo, hi)` needs `lo <= hi`, and `sign(x)` is -1, 0 or 1
+        // where `f64::signum` would give 1 for 0.
+        ("clamp", [x, lo, hi]) if lo <= hi => Ok(x.clamp(*lo, *hi)),
+        ("sign", [x]) if *x == 0.0 => Ok(0.0),
+        ("sign", [x]) => Ok(x.signum()),
+        // `approx(a, b, eps)` is 1 if `a` and `b` differ by at most `eps`,
+        // and 0 otherwise, so `approx(0.1 + 0.2, 0.3)` is 1.
+        ("approx", [a, b]) => Ok(truth((a - b).abs() <= APPROX_EPSILON)),
+        ("approx", [a, b, eps]) if *eps >= 0.0 => Ok(truth((a - b).abs() <= *eps)),
+        // Both need integral arguments and ignore their signs, so
+        // `gcd(-4, 6)` is 2. `gcd(0, 0)` is 0, as is `lcm` with a zero.
+        ("gcd", [a, b]) => Ok(gcd(integer(*a)?, integer(*b)?) as f64),
+        ("lcm", [a, b]) => lcm(integer(*a)?, integer(*b)?),
+        ("round", [x]) => Ok(x.round()),
+        ("round", [x, digits]) => Ok(round(*x, integer(*digits)?)),
+        ("sqrt", [_])
+        | ("ln", [_])
+        | ("log", [_, _])
+        | ("log2", [_])
+        | ("log10", [_])
+        | ("clamp", [_, _, _])
+        | ("approx", [_, _, _]) => Err(Error::OutOfDomain(name.to_owned())),
+        _ if FUNCTIONS.contains(&name) => Err(Error::WrongArgCount(name.to_owned())),
+        _ => Err(Error::UnknownFunction(name.to_owned())),
+    }
+}
+
+fn angle(x: f64, config: &EvalConfig) -> f64 {
+    match config.angle_mode {
+        AngleMode::Radians => x,
+        AngleMode::Degrees => x.to_radians(),
+    }
+}
+
+// Rounds half away from zero like `f64::round`, so `round(2.5)` is 3 and
+// `round(-2.5)` is -3. Negative `digits` round to tens, hundreds and so on.
+fn round(x: f64, digits: i64) -> f64 {
+    let digits = digits.clamp(-308, 308) as i32;
+    if digits < 0 {
+        let factor = 10f64.powi(-digits);
+        return (x / factor).round() * factor;
+    }
+    let factor = 10f64.powi(digits);
+    let scaled = x * factor;
+    // More digits than an f64 holds leave nothing to round.
+    if scaled.is_infinite() {
+        return x;
+    }
+    scaled.round() / factor
+}
+
+// Euclid's algorithm on the magnitudes.
+fn gcd(a: i64, b: i64) -> u64 {
+    let (mut a, mut b) = (a.unsigned_abs(), b.unsigned_abs());
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    a
+}
+
+fn lcm(a: i64, b: i64) -> Result<f64> {
+    if a == 0 || b == 0 {
+        return Ok(0.0);
+    }
+    let lcm = (a.unsigned_abs() / gcd(a, b))
+        .checked_mul(b.unsigned_abs())
+        .ok_or(Error::Overflow())?;
+    Ok(lcm as f64)
+}
+
+fn log(base: f64, x: f64) -> f64 {
+    if base == 2.0 {
+        x.log2()
+    } else if base == 10.0 {
+        x.log10()
+    } else {
+        x.log(base)
+    }
+}
+
+fn factorial(n: f64) -> Result<f64> {
+    if n < 0.0 {
+        return Err(Error::NegativeFactorial(n));
+    }
+    if n > 170.0 {
+        return Ok(f64::INFINITY);
+    }
+    if n.fract() == 0.0 {
+        return Ok((2..=n as u64).fold(1.0, |acc, i| acc * i as f64));
+    }
+    Ok(gamma(n + 1.0))
+}
+
+// Lanczos approximation (g = 7, n = 9), valid for x >= 0.5.
+fn gamma(x: f64) -> f64 {
+    const G: f64 = 7.0;
+    const COEFFS: [f64; 9] = [
+        0.999_999_999_999_809_9,
+        676.520_368_121_885_1,
+        -1_259.139_216_722_402_8,
+        771.323_428_777_653_1,
+        -176.615_029_162_140_6,
+        12.507_343_278_686_905,
+        -0.138_571_095_265_720_12,
+        9.984_369_578_019_572e-6,
+        1.505_632_735_149_311_6e-7,
+    ];
+    let x = x - 1.0;
+    let mut acc = COEFFS[0];
+    for (i, coeff) in COEFFS.iter().enumerate().skip(1) {
+        acc += coeff / (x + i as f64);
+    }
+    let t = x + G + 0.5;
+    (2.0 * std::f64::consts::PI).sqrt() * t.powf(x + 0.5) * (-t).exp() * acc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{eval_str, parser::parse};
+
+    #[test]
+    fn long_chain_parses_evaluates_and_drops() {
+        let input = vec!["1"; 200_000].join("+");
+        let expr = parse(&input).unwrap();
+        assert_eq!(expr.eval().unwrap(), 200_000.0);
+        drop(expr);
+    }
+
+    #[test]
+    fn deeply_nested_tree_evaluates_and_drops() {
+        let mut expr = Expr::Num(5.0);
+        for _ in 0..1_000_000 {
+            expr = Expr::Minus(Box::new(expr));
+        }
+        assert_eq!(expr.eval().unwrap(), 5.0);
+        drop(expr);
+    }
+
+    #[test]
+    fn powers_are_right_associative() {
+        assert_eq!(eval_str("2 ^ 10").unwrap(), 1024.0);
+        assert_eq!(eval_str("2 ^ 3 ^ 2").unwrap(), 512.0);
+        assert_eq!(eval_str("(2 ^ 3) ^ 2").unwrap(), 64.0);
+    }
+
+    #[test]
+    fn powers_bind_tighter_than_products() {
+        assert_eq!(eval_str("2 * 3 ^ 2").unwrap(), 18.0);
+        assert_eq!(eval_str("3 ^ 2 * 2").unwrap(), 18.0);
+        assert_eq!(eval_str("-2 ^ 2").unwrap(), -4.0);
+        assert_eq!(eval_str("(-2) ^ 2").unwrap(), 4.0);
+        assert_eq!(eval_str("2 ^ -1").unwrap(), 0.5);
+        assert_eq!(eval_str("-2 ^ 2 * 3").unwrap(), -12.0);
+    }
+
+    #[test]
+    fn modulo_binds_like_products() {
+        assert_eq!(eval_str("10 % 3").unwrap(), 1.0);
+        assert_eq!(eval_str("2 + 10 % 3").unwrap(), 3.0);
+        assert_eq!(eval_str("(2 + 10) % 3").unwrap(), 0.0);
+    }
+
+    #[test]
+    fn modulo_is_euclidean() {
+        assert_eq!(eval_str("-7 % 3").unwrap(), 2.0);
+        assert_eq!(eval_str("7 % -3").unwrap(), 1.0);
+        assert!(matches!(eval_str("1 % 0"), Err(Error::DivisionByZero())));
+    }
+
+    #[test]
+    fn factorials() {
+        assert_eq!(eval_str("5!").unwrap(), 120.0);
+        assert_eq!(eval_str("0!").unwrap(), 1.0);
+        assert_eq!(eval_str("3! + 1").unwrap(), 7.0);
+        assert!((eval_str("2.5!").unwrap() - 3.323_350_970_447_843).abs() < 1e-12);
+    }
+
+    #[test]
+    fn factorial_of_a_negative_number_fails() {
+        let err = eval_str("(-1)!").unwrap_err();
+        assert!(matches!(err, Error::NegativeFactorial(n) if n == -1.0));
+    }
+
+    #[test]
+    fn division_by_zero_fails() {
+        assert!(matches!(eval_str("1/0"), Err(Error::DivisionByZero())));
+        assert!(matches!(eval_str("0/0"), Err(Error::DivisionByZero())));
+        assert_eq!(eval_str("1/0.0000001").unwrap(), 1e7);
+    }
+
+    #[test]
+    fn calls() {
+        assert_eq!(eval_str("sqrt(16)").unwrap(), 4.0);
+        assert_eq!(eval_str("abs(-3)").unwrap(), 3.0);
+        assert_eq!(eval_str("floor(2.9)").unwrap(), 2.0);
+    }
+
+    #[test]
+    fn unknown_functions_and_wrong_arities_fail() {
+        let err = eval_str("foo(1)").unwrap_err();
+        assert!(matches!(err, Error::UnknownFunction(name) if name == "foo"));
+        let err = eval_str("sqrt(1, 2)").unwrap_err();
+        assert!(matches!(err, Error::WrongArgCount(name) if name == "sqrt"));
+    }
+
+    #[test]
+    fn trig_takes_radians() {
+        assert_eq!(eval_str("sin(0)").unwrap(), 0.0);
+        assert_eq!(eval_str("cos(0)").unwrap(), 1.0);
+        assert_eq!(eval_str("tan(0)").unwrap(), 0.0);
+        assert!((eval_str("sin(pi/2)").unwrap() - 1.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn degree_and_radian_conversions() {
+        assert!((eval_str("deg(pi)").unwrap() - 180.0).abs() < 1e-12);
+        assert!((eval_str("rad(180)").unwrap() - std::f64::consts::PI).abs() < 1e-12);
+    }
+
+    #[test]
+    fn logarithms() {
+        assert_eq!(eval_str("ln(e)").unwrap(), 1.0);
+        assert_eq!(eval_str("log(2, 8)").unwrap(), 3.0);
+        assert!((eval_str("log(10, 1000)").unwrap() - 3.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn logarithms_of_non_positive_numbers_fail() {
+        for input in ["ln(0)", "ln(-1)"] {
+            let err = eval_str(input).unwrap_err();
+            assert!(matches!(err, Error::OutOfDomain(name) if name == "ln"));
+        }
+    }
+
+    #[test]
+    fn right_leaning_chain_evaluates() {
+        let mut expr = Expr::Num(0.0);
+        for _ in 0..200_000 {
+            expr = Expr::Sub {
+                lhs: Box::new(Expr::Num(1.0)),
+                rhs: Box::new(expr),
+            };
+        }
+        assert_eq!(expr.eval().unwrap(), 0.0);
+    }
+
+    #[test]
+    fn assignments_persist_in_the_environment() {
+        let mut env = Environment::new();
+        assert_eq!(parse("x = 5").unwrap().eval_in(&mut env).unwrap(), 5.0);
+        assert_eq!(parse("x * 2").unwrap().eval_in(&mut env).unwrap(), 10.0);
+        assert_eq!(env.get("x"), Some(5.0));
+    }
+
+    #[test]
+    fn undefined_variables_fail() {
+        let err = parse("y + 1")
+            .unwrap()
+            .eval_in(&mut Environment::new())
+            .unwrap_err();
+        assert!(matches!(err, Error::UndefinedVariable(name) if name == "y"));
+    }
+
+    #[test]
+    fn comparisons_give_one_or_zero() {
+        let cases = [
+            ("3 > 2", 1.0),
+            ("2 > 3", 0.0),
+            ("2 < 3", 1.0),
+            ("2 <= 2", 1.0),
+            ("2 >= 3", 0.0),
+            ("1 == 1", 1.0),
+            ("1 != 1", 0.0),
+            ("(3 > 2) + (1 > 5)", 1.0),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(eval_str(input).unwrap(), expected, "{input}");
+        }
+    }
+
+    // Comparisons are left-associative, so the second compares the 0 or 1
+    // the first gives.
+    #[test]
+    fn chained_comparisons_compare_the_first_result() {
+        assert_eq!(eval_str("1 < 2 < 3").unwrap(), 1.0);
+        assert_eq!(eval_str("3 < 2 < 1").unwrap(), 1.0);
+        assert_eq!(eval_str("3 > 2 > 1").unwrap(), 0.0);
+    }
+
+    #[test]
+    fn conditionals_take_one_branch() {
+        assert_eq!(eval_str("(3 > 2) ? 10 : 20").unwrap(), 10.0);
+        assert_eq!(eval_str("(3 < 2) ? 10 : 20").unwrap(), 20.0);
+        // The branch not taken is not evaluated.
+        assert_eq!(eval_str("0 ? 1/0 : 2").unwrap(), 2.0);
+        assert_eq!(eval_str("1 ? 2 : 1/0").unwrap(), 2.0);
+    }
+
+    #[test]
+    fn nested_conditionals_are_right_associative() {
+        assert_eq!(eval_str("1 ? 2 : 3 ? 4 : 5").unwrap(), 2.0);
+        assert_eq!(eval_str("0 ? 2 : 0 ? 4 : 5").unwrap(), 5.0);
+        assert_eq!(eval_str("1 ? 0 ? 3 : 4 : 5").unwrap(), 4.0);
+    }
+
+    #[test]
+    fn angle_mode_changes_trig_arguments() {
+        let degrees = EvalConfig {
+            angle_mode: AngleMode::Degrees,
+            ..EvalConfig::default()
+        };
+        let expr = parse("sin(90)").unwrap();
+        assert_eq!(expr.eval_with_config(&degrees).unwrap(), 1.0);
+        assert_eq!(expr.eval().unwrap(), 90f64.sin());
+        let expr = parse("cos(pi)").unwrap();
+        assert_eq!(expr.eval().unwrap(), -1.0);
+        assert_eq!(
+            expr.eval_with_config(&degrees).unwrap(),
+            std::f64::consts::PI.to_radians().cos()
+        );
+    }
+
+    #[test]
+    fn floor_division_rounds_down() {
+        assert_eq!(eval_str("7 // 2").unwrap(), 3.0);
+        assert_eq!(eval_str("-7 // 2").unwrap(), -4.0);
+        assert_eq!(eval_str("1 + 7 // 2").unwrap(), 4.0);
+        assert!(matches!(eval_str("1 // 0"), Err(Error::DivisionByZero())));
+    }
+
+    #[test]
+    fn root_sign_is_sqrt() {
+        assert_eq!(eval_str("√9").unwrap(), 3.0);
+        assert_eq!(eval_str("√(4+5)").unwrap(), 3.0);
+        assert_eq!(eval_str("2 * √16").unwrap(), 8.0);
+        let err = eval_str("√-4").unwrap_err();
+        assert!(matches!(err, Error::OutOfDomain(name) if name == "sqrt"));
+    }
+
+    #[test]
+    fn bitwise_operators() {
+        assert_eq!(eval_str("6 & 3").unwrap(), 2.0);
+        assert_eq!(eval_str("5 | 2").unwrap(), 7.0);
+        assert_eq!(eval_str("5 ^^ 1").unwrap(), 4.0);
+        assert_eq!(eval_str("1 << 4").unwrap(), 16.0);
+        assert_eq!(eval_str("256 >> 4").unwrap(), 16.0);
+        // Shifts bind tighter than `&`, which binds tighter than `|`.
+        assert_eq!(eval_str("1 | 6 & 3 << 1").unwrap(), 7.0);
+    }
+
+    #[test]
+    fn bitwise_operators_need_integers() {
+        let err = eval_str("1.5 & 1").unwrap_err();
+        assert!(matches!(err, Error::NonIntegerOperand(n) if n == 1.5));
+    }
+
+    #[test]
+    fn error_on_nan_reports_nan_results() {
+        let config = EvalConfig {
+            error_on_nan: true,
+            ..EvalConfig::default()
+        };
+        for input in ["0*inf", "inf-inf", "nan", "(0*inf > 1) + 1"] {
+            let result = parse(input).unwrap().eval_with_config(&config);
+            assert!(matches!(result, Err(Error::NotANumber())), "{input}");
+        }
+        assert_eq!(
+            parse("1 + 1").unwrap().eval_with_config(&config).unwrap(),
+            2.0
+        );
+        assert!(eval_str("0*inf").unwrap().is_nan());
+    }
+
+    // A `%` with no operand after it is a percent sign, and otherwise modulo.
+    #[test]
+    fn percent_signs_divide_by_100() {
+        assert_eq!(eval_str("50%").unwrap(), 0.5);
+        assert_eq!(eval_str("200 * 5%").unwrap(), 10.0);
+        assert_eq!(eval_str("50% + 1").unwrap(), 1.5);
+        assert_eq!(eval_str("(50)%").unwrap(), 0.5);
+        assert_eq!(eval_str("10 % 3").unwrap(), 1.0);
+    }
+
+    #[test]
+    fn max_and_min_take_any_number_of_arguments() {
+        assert_eq!(eval_str("max(7)").unwrap(), 7.0);
+        assert_eq!(eval_str("max(1, 5)").unwrap(), 5.0);
+        assert_eq!(eval_str("max(1, 5, 3)").unwrap(), 5.0);
+        assert_eq!(eval_str("min(4, 2, 8)").unwrap(), 2.0);
+        assert_eq!(eval_str("max(1, min(2, 3))").unwrap(), 2.0);
+    }
+
+    #[test]
+    fn max_and_min_need_an_argument() {
+        for input in ["max()", "min()"] {
+            assert!(
+                matches!(eval_str(input), Err(Error::WrongArgCount(_))),
+                "{input}"
+            );
+        }
+    }
+
+    // Halves round away from zero.
+    #[test]
+    fn round_to_digits() {
+        assert_eq!(eval_str("round(2.5)").unwrap(), 3.0);
+        assert_eq!(eval_str("round(-2.5)").unwrap(), -3.0);
+        assert_eq!(eval_str("round(1.23456, 2)").unwrap(), 1.23);
+        assert_eq!(eval_str("round(1234.5, -2)").unwrap(), 1200.0);
+        assert!(matches!(
+            eval_str("round(1, 2, 3)"),
+            Err(Error::WrongArgCount(_))
+        ));
+    }
+
+    #[test]
+    fn eval_checked_reports_overflow() {
+        for input in ["1e308 * 10", "10 ^ 400", "2 ^ 2000"] {
+            let result = parse(input).unwrap().eval_checked();
+            assert!(matches!(result, Err(Error::Overflow())), "{input}");
+        }
+    }
+
+    #[test]
+    fn eval_checked_lets_infinite_operands_through() {
+        assert_eq!(parse("inf").unwrap().eval_checked().unwrap(), f64::INFINITY);
+        assert_eq!(
+            parse("inf * 2").unwrap().eval_checked().unwrap(),
+            f64::INFINITY
+        );
+        assert_eq!(parse("1e300 * 10").unwrap().eval_checked().unwrap(), 1e301);
+    }
+
+    #[test]
+    fn eval_checked_looks_inside_defined_functions() {
+        let mut env = Environment::new();
+        run(&mut env, "f(x) = x * 10 - x * 10").unwrap();
+        let result = parse("f(1e308)").unwrap().eval_checked_in(&mut env);
+        assert!(matches!(result, Err(Error::Overflow())));
+        assert!(run(&mut env, "f(1e308)").unwrap().is_nan());
+    }
+
+    #[test]
+    fn operands_are_evaluated_left_to_right() {
+        let mut leaves = Vec::new();
+        let expr = parse("(1+2)*(3+4)").unwrap();
+        let value = expr
+            .eval_recording(&mut Environment::new(), &mut leaves)
+            .unwrap();
+        assert_eq!(value, 21.0);
+        assert_eq!(leaves, [1.0, 2.0, 3.0, 4.0]);
+    }
+
+    #[test]
+    fn assignments_happen_in_evaluation_order() {
+        let mut leaves = Vec::new();
+        let expr = parse("(x = 2) * x + (x = 5) - x").unwrap();
+        let value = expr
+            .eval_recording(&mut Environment::new(), &mut leaves)
+            .unwrap();
+        assert_eq!(value, 4.0);
+        assert_eq!(leaves, [2.0, 2.0, 5.0, 5.0]);
+    }
+
+    #[test]
+    fn recording_includes_defined_function_bodies() {
+        let mut env = Environment::new();
+        run(&mut env, "f(x) = x * 2 + k").unwrap();
+        env.set("k", 7.0);
+        let mut leaves = Vec::new();
+        let value = parse("1 + f(3)")
+            .unwrap()
+            .eval_recording(&mut env, &mut leaves)
+            .unwrap();
+        assert_eq!(value, 14.0);
+        assert_eq!(leaves, [1.0, 3.0, 3.0, 2.0, 7.0]);
+    }
+
+    #[test]
+    fn financial_helpers() {
+        assert_eq!(eval_str("pct(50)").unwrap(), 0.5);
+        assert_eq!(eval_str("avg(2, 4, 6)").unwrap(), 4.0);
+        assert!((eval_str("compound(1000, 0.05, 2)").unwrap() - 1102.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn financial_helpers_check_their_arity() {
+        for input in ["pct(1, 2)", "avg()", "compound(1, 2)"] {
+            assert!(
+                matches!(eval_str(input), Err(Error::WrongArgCount(_))),
+                "{input}"
+            );
+        }
+    }
+
+    #[test]
+    fn eval_with_resolves_through_the_closure() {
+        let resolver = |name: &str| (name == "x").then_some(4.0);
+        assert_eq!(parse("x * 2").unwrap().eval_with(resolver).unwrap(), 8.0);
+        let err = parse("x + y").unwrap().eval_with(resolver).unwrap_err();
+        assert!(matches!(err, Error::UndefinedVariable(name) if name == "y"));
+    }
+
+    #[test]
+    fn assignments_shadow_the_resolver() {
+        let resolver = |name: &str| (name == "x").then_some(4.0);
+        let expr = parse("x + (x = 1) + x").unwrap();
+        assert_eq!(expr.eval_with(resolver).unwrap(), 6.0);
+    }
+
+    #[test]
+    fn chained_signs() {
+        assert_eq!(eval_str("--5").unwrap(), 5.0);
+        assert_eq!(eval_str("- - 5").unwrap(), 5.0);
+        assert_eq!(eval_str("-+-5").unwrap(), 5.0);
+        assert_eq!(eval_str("-+5").unwrap(), -5.0);
+        assert_eq!(eval_str("2--3").unwrap(), 5.0);
+        assert_eq!(eval_str("2*-3").unwrap(), -6.0);
+        assert_eq!(eval_str("2^-1").unwrap(), 0.5);
+    }
+
+    #[test]
+    fn bars_take_the_absolute_value() {
+        assert_eq!(eval_str("|-3|").unwrap(), 3.0);
+        assert_eq!(eval_str("|3 - 7|").unwrap(), 4.0);
+        assert_eq!(eval_str("|-2| + |3|").unwrap(), 5.0);
+        assert_eq!(eval_str("||1| - 2|").unwrap(), 1.0);
+    }
+
+    #[test]
+    fn unclosed_bars_point_at_the_opening_one() {
+        let err = eval_str("1 + |1").unwrap_err();
+        assert!(matches!(err, Error::UnclosedBar(span) if span.start == 4));
+    }
+
+    #[test]
+    fn clamp_keeps_values_in_range() {
+        assert_eq!(eval_str("clamp(-1, 0, 3)").unwrap(), 0.0);
+        assert_eq!(eval_str("clamp(2, 0, 3)").unwrap(), 2.0);
+        assert_eq!(eval_str("clamp(5, 0, 3)").unwrap(), 3.0);
+        assert!(matches!(
+            eval_str("clamp(1, 3, 0)"),
+            Err(Error::OutOfDomain(_))
+        ));
+        assert!(matches!(
+            eval_str("clamp(1, 2)"),
+            Err(Error::WrongArgCount(_))
+        ));
+    }
+
+    #[test]
+    fn sign_of_negative_zero_and_positive_numbers() {
+        assert_eq!(eval_str("sign(-2)").unwrap(), -1.0);
+        assert_eq!(eval_str("sign(0)").unwrap(), 0.0);
+        assert_eq!(eval_str("sign(3)").unwrap(), 1.0);
+    }
+
+    #[test]
+    fn pow_exp_and_base_logarithms() {
+        assert_eq!(eval_str("pow(2, 10)").unwrap(), 1024.0);
+        assert_eq!(eval_str("pow(2, 10)").unwrap(), eval_str("2 ^ 10").unwrap());
+        assert_eq!(eval_str("exp(0)").unwrap(), 1.0);
+        assert_eq!(eval_str("exp(1)").unwrap(), std::f64::consts::E);
+        assert_eq!(eval_str("log2(8)").unwrap(), 3.0);
+        assert_eq!(eval_str("log10(1000)").unwrap(), 3.0);
+    }
+
+    #[test]
+    fn pow_exp_and_base_logarithms_check_their_arguments() {
+        for input in [
+            "pow(2)",
+            "pow(2, 3, 4)",
+            "exp()",
+            "exp(1, 2)",
+            "log2()",
+            "log10(1, 2)",
+        ] {
+            assert!(
+                matches!(eval_str(input), Err(Error::WrongArgCount(_))),
+                "{input}"
+            );
+        }
+        assert!(matches!(eval_str("log2(0)"), Err(Error::OutOfDomain(_))));
+        assert!(matches!(eval_str("log10(-1)"), Err(Error::OutOfDomain(_))));
+    }
+
+    #[test]
+    fn ans_indexes_earlier_results() {
+        let mut env = Environment::new();
+        for value in [2.0, 10.0, 0.5] {
+            env.push_result(value);
+        }
+        let mut eval = |input: &str| parse(input).unwrap().eval_in(&mut env);
+        assert_eq!(eval("ans[1] + ans[2]").unwrap(), 12.0);
+        assert_eq!(eval("ans[1 + 2] * 4").unwrap(), 2.0);
+        assert!(matches!(eval("ans[0]"), Err(Error::NoSuchResult(n)) if n == 0.0));
+        assert!(matches!(eval("ans[4]"), Err(Error::NoSuchResult(n)) if n == 4.0));
+        assert!(matches!(eval("ans[1e30]"), Err(Error::NoSuchResult(_))));
+        assert!(matches!(eval("ans[-1]"), Err(Error::NoSuchResult(_))));
+        assert!(matches!(eval("ans[1.5]"), Err(Error::NonIntegerOperand(_))));
+    }
+
+    #[test]
+    fn clear_keeps_the_results() {
+        let mut env = Environment::new();
+        env.push_result(7.0);
+        env.clear();
+        assert_eq!(env.result(1), Some(7.0));
+        assert_eq!(env.result(0), None);
+        assert_eq!(env.result(2), None);
+    }
+
+    #[test]
+    fn logical_operators_give_one_or_zero() {
+        let cases = [
+            ("1 and 0", 0.0),
+            ("2 and 3", 1.0),
+            ("0 or 2", 1.0),
+            ("0 or 0", 0.0),
+            ("-1 or 0", 1.0),
+            ("not 0", 1.0),
+            ("not 3", 0.0),
+            ("not not 5", 1.0),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(eval_str(input).unwrap(), expected, "{input}");
+        }
+    }
+
+    #[test]
+    fn logical_operators_bind_loosest() {
+        assert_eq!(eval_str("1 or 0 and 0").unwrap(), 1.0);
+        assert_eq!(eval_str("1 < 2 and 3 < 2").unwrap(), 0.0);
+        assert_eq!(eval_str("not 1 + 1").unwrap(), 0.0);
+        assert_eq!(eval_str("not 1 and 0").unwrap(), 0.0);
+    }
+
+    #[test]
+    fn logical_operators_short_circuit() {
+        assert_eq!(eval_str("0 and 1 / 0").unwrap(), 0.0);
+        assert_eq!(eval_str("1 or 1 / 0").unwrap(), 1.0);
+        assert!(matches!(
+            eval_str("1 and 1 / 0"),
+            Err(Error::DivisionByZero())
+        ));
+        assert!(matches!(
+            eval_str("0 or 1 / 0"),
+            Err(Error::DivisionByZero())
+        ));
+        let mut env = Environment::new();
+        parse("0 and (x = 1)").unwrap().eval_in(&mut env).unwrap();
+        assert_eq!(env.get("x"), None);
+    }
+
+    fn with_recursion_limit(recursion_limit: usize) -> Environment {
+        Environment::with_config(EvalConfig {
+            recursion_limit,
+            ..EvalConfig::default()
+        })
+    }
+
+    #[test]
+    fn recursion_limit_does_not_change_results() {
+        let inputs = [
+            "1 + 2 * 3 - 4 / 5",
+            "(x = 2) * x + max(x, 3, -1)",
+            "1 < 2 ? 10 : 1 / 0",
+            "0 and 1 / 0",
+            "-(-(-(2 ^ 3 ^ 2)))",
+            "f(x) = x * 2",
+            "3! + |1 - 5|",
+        ];
+        for input in inputs {
+            let expr = parse(input).unwrap();
+            let expected = expr.eval_in(&mut with_recursion_limit(0)).unwrap();
+            for limit in [1, 2, 3, 64, usize::MAX] {
+                let value = expr.eval_in(&mut with_recursion_limit(limit)).unwrap();
+                assert_eq!(value.to_bits(), expected.to_bits(), "{input} at {limit}");
+            }
+        }
+        for limit in [0, 2, 64] {
+            let result = parse("1 + 2 * (3 / 0)")
+                .unwrap()
+                .eval_in(&mut with_recursion_limit(limit));
+            assert!(matches!(result, Err(Error::DivisionByZero())), "{limit}");
+        }
+    }
+
+    #[test]
+    fn deep_trees_fall_back_to_the_explicit_stack() {
+        let mut expr = Expr::Num(1.0);
+        for _ in 0..50_000 {
+            expr = Expr::Add {
+                lhs: Box::new(Expr::Num(1.0)),
+                rhs: Box::new(expr),
+            };
+        }
+        for limit in [0, 64, 1_000] {
+            let value = expr.eval_in(&mut with_recursion_limit(limit)).unwrap();
+            assert_eq!(value, 50_001.0, "{limit}");
+        }
+    }
+
+    #[test]
+    fn approx_compares_within_a_tolerance() {
+        assert_eq!(eval_str("approx(1, 1.05, 0.1)").unwrap(), 1.0);
+        assert_eq!(eval_str("approx(1, 1.5, 0.1)").unwrap(), 0.0);
+        assert_eq!(eval_str("approx(1, 1.1, 0.25)").unwrap(), 1.0);
+        assert_eq!(eval_str("approx(2, 2, 0)").unwrap(), 1.0);
+        assert!(matches!(
+            eval_str("approx(1, 2, -1)"),
+            Err(Error::OutOfDomain(_))
+        ));
+    }
+
+    #[test]
+    fn approx_defaults_to_a_tiny_tolerance() {
+        assert_eq!(eval_str("0.1 + 0.2 == 0.3").unwrap(), 0.0);
+        assert_eq!(eval_str("approx(0.1 + 0.2, 0.3)").unwrap(), 1.0);
+        assert_eq!(eval_str("approx(1, 1.000001)").unwrap(), 0.0);
+        for input in ["approx()", "approx(1)", "approx(1, 2, 3, 4)"] {
+            assert!(
+                matches!(eval_str(input), Err(Error::WrongArgCount(_))),
+                "{input}"
+            );
+        }
+    }
+
+    #[test]
+    fn gcd_and_lcm() {
+        let cases = [
+            ("gcd(12, 18)", 6.0),
+            ("lcm(4, 6)", 12.0),
+            ("gcd(7, 9)", 1.0),
+            ("lcm(7, 9)", 63.0),
+            ("gcd(-4, 6)", 2.0),
+            ("lcm(-4, 6)", 12.0),
+            ("gcd(0, 5)", 5.0),
+            ("gcd(0, 0)", 0.0),
+            ("lcm(0, 5)", 0.0),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(eval_str(input).unwrap(), expected, "{input}");
+        }
+    }
+
+    #[test]
+    fn gcd_and_lcm_need_integers() {
+        assert!(matches!(
+            eval_str("gcd(4.5, 6)"),
+            Err(Error::NonIntegerOperand(n)) if n == 4.5
+        ));
+        assert!(matches!(
+            eval_str("lcm(4, 0.5)"),
+            Err(Error::NonIntegerOperand(_))
+        ));
+        assert!(matches!(eval_str("lcm(2^62, 5)"), Err(Error::Overflow())));
+        assert!(matches!(eval_str("gcd(1)"), Err(Error::WrongArgCount(_))));
+    }
+
+    fn trace(input: &str) -> (f64, Vec<String>) {
+        parse(input).unwrap().eval_trace().unwrap()
+    }
+
+    #[test]
+    fn trace_lists_each_step_in_order() {
+        assert_eq!(
+            trace("(1 + 2) * 4"),
+            (12.0, vec!["1 + 2 = 3".to_owned(), "3 * 4 = 12".to_owned()])
+        );
+        assert_eq!(
+            trace("2 ^ 3 - max(1, 5) / 2").1,
+            ["2 ^ 3 = 8", "max(1, 5) = 5", "5 / 2 = 2.5", "8 - 2.5 = 5.5"]
+        );
+    }
+
+    #[test]
+    fn trace_skips_leaves_signs_and_untaken_branches() {
+        assert_eq!(trace("7"), (7.0, Vec::new()));
+        assert_eq!(trace("-3 * 2").1, ["(-3) * 2 = -6"]);
+        assert_eq!(trace("3! + 1").1, ["3! = 6", "6 + 1 = 7"]);
+        assert_eq!(trace("1 < 2 ? 3 + 4 : 5 * 6").1, ["1 < 2 = 1", "3 + 4 = 7"]);
+        assert!(matches!(
+            parse("1 + 2 / 0").unwrap().eval_trace(),
+            Err(Error::DivisionByZero())
+        ));
+    }
+
+    #[test]
+    fn trace_steps_into_defined_functions() {
+        let mut env = Environment::new();
+        run(&mut env, "f(x) = x * 2").unwrap();
+        let (value, steps) = parse("f(1 + 2) - 1")
+            .unwrap()
+            .eval_trace_in(&mut env)
+            .unwrap();
+        assert_eq!(value, 5.0);
+        assert_eq!(steps, ["1 + 2 = 3", "3 * 2 = 6", "f(3) = 6", "6 - 1 = 5"]);
+    }
+
+    fn run(env: &mut Environment, input: &str) -> Result<f64> {
+        parse(input).unwrap().eval_in(env)
+    }
+
+    #[test]
+    fn defined_functions_can_be_called() {
+        let mut env = Environment::new();
+        run(&mut env, "f(x) = x * x").unwrap();
+        assert_eq!(run(&mut env, "f(5)").unwrap(), 25.0);
+        assert_eq!(run(&mut env, "f(f(2)) + 1").unwrap(), 17.0);
+        assert!(matches!(
+            run(&mut env, "f(1, 2)"),
+            Err(Error::WrongArgCount(name)) if name == "f"
+        ));
+        assert!(matches!(
+            run(&mut env, "g(1)"),
+            Err(Error::UnknownFunction(name)) if name == "g"
+        ));
+    }
+
+    #[test]
+    fn redefining_a_function_replaces_it() {
+        let mut env = Environment::new();
+        run(&mut env, "f(x) = x * x").unwrap();
+        run(&mut env, "f(y) = y + 1").unwrap();
+        assert_eq!(run(&mut env, "f(5)").unwrap(), 6.0);
+    }
+
+    #[test]
+    fn parameters_shadow_variables_only_during_the_call() {
+        let mut env = Environment::new();
+        run(&mut env, "x = 3").unwrap();
+        run(&mut env, "k = 10").unwrap();
+        run(&mut env, "f(x) = x * k").unwrap();
+        assert_eq!(run(&mut env, "f(2)").unwrap(), 20.0);
+        assert_eq!(env.get("x"), Some(3.0));
+        run(&mut env, "k = 100").unwrap();
+        assert_eq!(run(&mut env, "f(2)").unwrap(), 200.0);
+    }
+
+    #[test]
+    fn recursion_is_bounded() {
+        let mut env = Environment::new();
+        run(&mut env, "r(n) = n < 1 ? 0 : 1 + r(n - 1)").unwrap();
+        assert_eq!(run(&mut env, "r(100)").unwrap(), 100.0);
+        assert!(matches!(
+            run(&mut env, "r(100000)"),
+            Err(Error::RecursionLimit(name)) if name == "r"
+        ));
+        // A failed call leaves nothing behind.
+        assert_eq!(run(&mut env, "r(3)").unwrap(), 3.0);
+        assert_eq!(env.get("n"), None);
+    }
+
+    #[test]
+    fn subtraction_and_division_are_left_associative() {
+        let cases = [
+            ("10 - 2 - 3", 5.0),
+            ("10 - 2 - 3 - 4", 1.0),
+            ("100 / 10 / 2", 5.0),
+            ("2 / 4 / 8", 0.0625),
+            ("100 // 7 // 2", 7.0),
+            ("100 % 7 % 3", 2.0),
+            ("10 - 2 + 3", 11.0),
+            ("100 / 10 * 2", 20.0),
+            ("64 >> 2 >> 1", 8.0),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(eval_str(input).unwrap(), expected, "{input}");
+        }
+    }
+
+    #[test]
+    fn long_left_chains_are_left_associative() {
+        let input = format!("1000{}", " - 1".repeat(10_000));
+        assert_eq!(eval_str(&input).unwrap(), -9000.0);
+        let input = format!("1024{}", " / 2".repeat(10));
+        assert_eq!(eval_str(&input).unwrap(), 1.0);
+    }
+
+    #[test]
+    fn rounding_family() {
+        let cases = [
+            ("floor", 2.0, -3.0),
+            ("ceil", 3.0, -2.0),
+            ("round", 3.0, -3.0),
+            ("trunc", 2.0, -2.0),
+            ("abs", 2.7, 2.7),
+        ];
+        for (name, positive, negative) in cases {
+            assert_eq!(
+                eval_str(&format!("{name}(2.7)")).unwrap(),
+                positive,
+                "{name}"
+            );
+            assert_eq!(
+                eval_str(&format!("{name}(-2.7)")).unwrap(),
+                negative,
+                "{name}"
+            );
+        }
+        assert!((eval_str("fract(2.7)").unwrap() - 0.7).abs() < 1e-12);
+        assert!((eval_str("fract(-2.7)").unwrap() + 0.7).abs() < 1e-12);
+        assert_eq!(eval_str("round(2.5)").unwrap(), 3.0);
+        assert_eq!(eval_str("round(-2.5)").unwrap(), -3.0);
+    }
+
+    #[test]
+    fn rounding_family_takes_one_argument() {
+        for name in ["floor", "ceil", "trunc", "abs", "fract"] {
+            for args in ["", "1, 2"] {
+                let input = format!("{name}({args})");
+                assert!(
+                    matches!(eval_str(&input), Err(Error::WrongArgCount(n)) if n == name),
+                    "{input}"
+                );
+            }
+        }
+    }
+}
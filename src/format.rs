@@ -0,0 +1,219 @@
+#[cfg(feature = "bigdecimal")]
+use bigdecimal::{BigDecimal, RoundingMode};
+
+#[cfg(feature = "units")]
+use crate::units::Quantity;
+use crate::{complex::Complex, value::Value};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Notation {
+    #[default]
+    Fixed,
+    Scientific,
+}
+
+// How results are printed. Without a precision floats print with as many
+// digits as it takes to round-trip them.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FormatConfig {
+    pub precision: Option<usize>,
+    pub notation: Notation,
+    pub trim_zeros: bool,
+}
+
+impl Default for FormatConfig {
+    fn default() -> Self {
+        FormatConfig {
+            precision: None,
+            notation: Notation::Fixed,
+            trim_zeros: true,
+        }
+    }
+}
+
+impl FormatConfig {
+    // Integers print exactly in fixed notation whatever the precision.
+    pub fn format(&self, value: Value) -> String {
+        // Negative zero prints as `0` in every notation.
+        let value = if value == Value::Float(0.0) {
+            Value::Float(0.0)
+        } else {
+            value
+        };
+        let formatted = match (value, self.notation, self.precision) {
+            (Value::Int(int), Notation::Fixed, _) => return int.to_string(),
+            (_, Notation::Fixed, None) => format_result(f64::from(value)),
+            (_, Notation::Fixed, Some(precision)) => format!("{:.precision$}", f64::from(value)),
+            (_, Notation::Scientific, None) => format!("{:e}", f64::from(value)),
+            (_, Notation::Scientific, Some(precision)) => {
+                format!("{:.precision$e}", f64::from(value))
+            }
+        };
+        if self.trim_zeros {
+            trim_zeros(&formatted)
+        } else {
+            formatted
+        }
+    }
+}
+
+impl FormatConfig {
+    // Laid out like `Complex` displays, with each part formatted as a float.
+    pub fn format_complex(&self, value: Complex) -> String {
+        let part = |x: f64| self.format(Value::Float(x));
+        let Complex { re, im } = value;
+        if im == 0.0 {
+            part(re)
+        } else if re == 0.0 {
+            format!("{}i", part(im))
+        } else if im < 0.0 {
+            format!("{} - {}i", part(re), part(-im))
+        } else {
+            format!("{} + {}i", part(re), part(im))
+        }
+    }
+}
+
+#[cfg(feature = "units")]
+impl FormatConfig {
+    // The value formatted as a float, followed by its units.
+    pub fn format_quantity(&self, value: Quantity) -> String {
+        let formatted = self.format(Value::Float(value.value));
+        if value.is_dimensionless() {
+            formatted
+        } else {
+            format!("{formatted} {}", value.units())
+        }
+    }
+}
+
+#[cfg(feature = "bigdecimal")]
+impl FormatConfig {
+    // Decimals always print in fixed notation, with every digit unless a
+    // precision rounds them half away from zero.
+    pub fn format_decimal(&self, value: &BigDecimal) -> String {
+        let value = match self.precision {
+            Some(precision) => value.with_scale_round(precision as i64, RoundingMode::HalfUp),
+            None => value.clone(),
+        };
+        if self.trim_zeros {
+            value.normalized().to_plain_string()
+        } else {
+            value.to_plain_string()
+        }
+    }
+}
+
+// Prints integral values without a fraction, so `4.0` is `4` and `1e20` is
+// `100000000000000000000`, and anything else with as many digits as it takes
+// to round-trip it. Negative zero prints as `0`.
+pub fn format_result(x: f64) -> String {
+    if x == 0.0 {
+        return "0".to_owned();
+    }
+    x.to_string()
+}
+
+// Drops trailing zeros after the decimal point, and the point itself when
+// nothing is left after it: `0.5000` becomes `0.5`, `2.000e3` becomes `2e3`.
+fn trim_zeros(formatted: &str) -> String {
+    let (mantissa, exponent) = match formatted.find('e') {
+        Some(e) => formatted.split_at(e),
+        None => (formatted, ""),
+    };
+    if !mantissa.contains('.') {
+        return formatted.to_owned();
+    }
+    let mantissa = mantissa.trim_end_matches('0').trim_end_matches('.');
+    format!("{mantissa}{exponent}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(precision: Option<usize>, notation: Notation, trim_zeros: bool) -> FormatConfig {
+        FormatConfig {
+            precision,
+            notation,
+            trim_zeros,
+        }
+    }
+
+    #[test]
+    fn precision_rounds_floats() {
+        let third = Value::Float(1.0 / 3.0);
+        assert_eq!(
+            config(Some(4), Notation::Fixed, true).format(third),
+            "0.3333"
+        );
+        assert_eq!(FormatConfig::default().format(third), "0.3333333333333333");
+        assert_eq!(
+            config(Some(0), Notation::Fixed, true).format(Value::Float(2.5)),
+            "2"
+        );
+    }
+
+    #[test]
+    fn trailing_zeros_are_trimmed_unless_asked_for() {
+        let value = Value::Float(2.5);
+        assert_eq!(config(Some(2), Notation::Fixed, true).format(value), "2.5");
+        assert_eq!(
+            config(Some(2), Notation::Fixed, false).format(value),
+            "2.50"
+        );
+    }
+
+    #[test]
+    fn scientific_notation() {
+        let scientific = config(Some(3), Notation::Scientific, true);
+        assert_eq!(scientific.format(Value::Float(12345.678)), "1.235e4");
+        assert_eq!(scientific.format(Value::Int(1500)), "1.5e3");
+        let untrimmed = config(Some(2), Notation::Scientific, false);
+        assert_eq!(untrimmed.format(Value::Int(1500)), "1.50e3");
+    }
+
+    #[test]
+    fn integers_print_exactly() {
+        let config = config(Some(2), Notation::Fixed, true);
+        assert_eq!(config.format(Value::Int(7)), "7");
+        assert_eq!(config.format(Value::Int(i64::MAX)), "9223372036854775807");
+        assert_eq!(
+            FormatConfig::default().format(Value::Float(1e20)),
+            "100000000000000000000"
+        );
+        assert_eq!(FormatConfig::default().format(Value::Float(-0.0)), "0");
+    }
+
+    #[cfg(feature = "bigdecimal")]
+    #[test]
+    fn decimals_print_in_fixed_notation() {
+        use std::str::FromStr;
+
+        let third = BigDecimal::from_str("0.33333333333333333333").unwrap();
+        assert_eq!(
+            FormatConfig::default().format_decimal(&third),
+            "0.33333333333333333333"
+        );
+        let config = config(Some(3), Notation::Scientific, true);
+        assert_eq!(config.format_decimal(&third), "0.333");
+        let half = BigDecimal::from_str("0.0005").unwrap();
+        assert_eq!(config.format_decimal(&half), "0.001");
+        let config = FormatConfig {
+            precision: Some(2),
+            trim_zeros: false,
+            ..FormatConfig::default()
+        };
+        assert_eq!(config.format_decimal(&BigDecimal::from(3)), "3.00");
+    }
+
+    #[test]
+    fn format_result_trims_integral_values() {
+        assert_eq!(format_result(4.0), "4");
+        assert_eq!(format_result(0.5), "0.5");
+        assert_eq!(format_result(1e20), "100000000000000000000");
+        assert_eq!(format_result(-0.0), "0");
+        assert_eq!(format_result(-2.25), "-2.25");
+        assert_eq!(format_result(0.1 + 0.2), "0.30000000000000004");
+    }
+}
@@ -0,0 +1,382 @@
+use std::{
+    collections::BTreeSet,
+    hash::{Hash, Hasher},
+    mem,
+};
+
+use crate::{eval::FUNCTIONS, parser::Expr};
+
+impl Expr {
+    // Every variable the expression reads. Assignment targets only count if
+    // they are also read somewhere, and the bodies of definitions are left
+    // out since they only read their parameter when called.
+    pub fn vars(&self) -> BTreeSet<String> {
+        let mut vars = BTreeSet::new();
+        let mut pending = vec![self];
+        while let Some(expr) = pending.pop() {
+            match expr {
+                Expr::Var(name) => {
+                    vars.insert(name.clone());
+                }
+                Expr::Define { .. } => {}
+                _ => pending.extend(expr.children()),
+            }
+        }
+        vars
+    }
+
+    // Whether the expression reads the named variable, as `vars` would list
+    // it, stopping at the first read.
+    pub fn reads(&self, name: &str) -> bool {
+        let mut pending = vec![self];
+        while let Some(expr) = pending.pop() {
+            match expr {
+                Expr::Var(var) if var == name => return true,
+                Expr::Define { .. } => {}
+                _ => pending.extend(expr.children()),
+            }
+        }
+        false
+    }
+
+    // Whether the value is the same in every environment, so it can be
+    // cached: the expression reads no variable and no earlier result through
+    // `ans[n]`, calls no defined function, and assigns or defines nothing.
+    // Every builtin function is deterministic, though trig calls still depend
+    // on the angle mode.
+    pub fn is_constant(&self) -> bool {
+        let mut pending = vec![self];
+        while let Some(expr) = pending.pop() {
+            match expr {
+                Expr::Var(_) | Expr::Assign { .. } | Expr::Define { .. } => return false,
+                Expr::Call { name, .. } if !FUNCTIONS.contains(&name.as_str()) => return false,
+                _ => pending.extend(expr.children()),
+            }
+        }
+        true
+    }
+
+    // How many nodes the tree has, so `1 + 2 * 3` has 5.
+    pub fn count_nodes(&self) -> usize {
+        let mut count = 0;
+        let mut pending = vec![self];
+        while let Some(expr) = pending.pop() {
+            count += 1;
+            pending.extend(expr.children());
+        }
+        count
+    }
+
+    // How many nodes the longest path from the root to a leaf passes
+    // through, so a lone number has depth 1 and `1 + 2 * 3` has depth 3.
+    pub fn depth(&self) -> usize {
+        let mut depth = 0;
+        let mut pending = vec![(self, 1)];
+        while let Some((expr, level)) = pending.pop() {
+            depth = depth.max(level);
+            pending.extend(expr.children().into_iter().map(|child| (child, level + 1)));
+        }
+        depth
+    }
+
+    // `==` except that NaN leaves compare equal to each other.
+    pub fn structurally_eq(&self, other: &Expr) -> bool {
+        self.eq_by(other, |lhs, rhs| lhs == rhs || lhs.is_nan() && rhs.is_nan())
+    }
+
+    // Compares the trees node by node without recursing, with `num_eq`
+    // deciding whether two numbers match.
+    fn eq_by(&self, other: &Expr, num_eq: impl Fn(f64, f64) -> bool) -> bool {
+        let mut pending = vec![(self, other)];
+        while let Some((lhs, rhs)) = pending.pop() {
+            let same = match (lhs, rhs) {
+                (Expr::Num(lhs), Expr::Num(rhs)) => num_eq(*lhs, *rhs),
+                (Expr::Var(lhs), Expr::Var(rhs)) => lhs == rhs,
+                (
+                    Expr::Call { name: lhs, args },
+                    Expr::Call {
+                        name: rhs,
+                        args: other,
+                    },
+                ) => lhs == rhs && args.len() == other.len(),
+                (Expr::Assign { name: lhs, .. }, Expr::Assign { name: rhs, .. }) => lhs == rhs,
+                (
+                    Expr::Define { name, param, .. },
+                    Expr::Define {
+                        name: other_name,
+                        param: other_param,
+                        ..
+                    },
+                ) => name == other_name && param == other_param,
+                _ => mem::discriminant(lhs) == mem::discriminant(rhs),
+            };
+            if !same {
+                return false;
+            }
+            pending.extend(lhs.children().into_iter().zip(rhs.children()));
+        }
+        true
+    }
+}
+
+impl PartialEq for Expr {
+    fn eq(&self, other: &Expr) -> bool {
+        self.eq_by(other, |lhs, rhs| lhs == rhs)
+    }
+}
+
+// Hashes numbers by their bits, except that `-0.0` hashes like `0.0` since
+// the two are equal, and every NaN hashes alike, so trees that are `==`,
+// `structurally_eq` or equal as an `ExprKey` hash the same. The walk does not
+// recurse, so a deep tree cannot overflow the stack.
+impl Hash for Expr {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        let mut pending = vec![self];
+        while let Some(expr) = pending.pop() {
+            mem::discriminant(expr).hash(state);
+            match expr {
+                Expr::Num(num) if *num == 0.0 => 0u64.hash(state),
+                Expr::Num(num) if num.is_nan() => f64::NAN.to_bits().hash(state),
+                Expr::Num(num) => num.to_bits().hash(state),
+                Expr::Var(name) | Expr::Assign { name, .. } => name.hash(state),
+                Expr::Call { name, args } => {
+                    name.hash(state);
+                    args.len().hash(state);
+                }
+                Expr::Define { name, param, .. } => {
+                    name.hash(state);
+                    param.hash(state);
+                }
+                _ => {}
+            }
+            pending.extend(expr.children());
+        }
+    }
+}
+
+// A tree as a `HashMap` key. `Expr` is not `Eq`, since a NaN is not equal to
+// itself; here numbers are equal when their bits are, with `-0.0` and `0.0`
+// equal to match `Hash`, so a tree holding a NaN is found again.
+#[derive(Clone, Debug)]
+pub struct ExprKey(pub Expr);
+
+impl PartialEq for ExprKey {
+    fn eq(&self, other: &ExprKey) -> bool {
+        let bits = |num: f64| if num == 0.0 { 0 } else { num.to_bits() };
+        self.0.eq_by(&other.0, |lhs, rhs| bits(lhs) == bits(rhs))
+    }
+}
+
+impl Eq for ExprKey {}
+
+impl Hash for ExprKey {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.hash(state);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::{hash_map::DefaultHasher, HashMap},
+        hash::{Hash, Hasher},
+    };
+
+    use super::ExprKey;
+    use crate::parser::{parse, Expr};
+
+    fn vars(input: &str) -> Vec<String> {
+        parse(input).unwrap().vars().into_iter().collect()
+    }
+
+    #[test]
+    fn collects_each_variable_once() {
+        assert!(vars("1 + 2").is_empty());
+        assert_eq!(vars("x * 2"), ["x"]);
+        assert_eq!(vars("y * x + x ^ y"), ["x", "y"]);
+        assert_eq!(vars("max(a, sqrt(b))"), ["a", "b"]);
+    }
+
+    #[test]
+    fn reads_agrees_with_vars() {
+        for input in [
+            "1 + 2",
+            "x * 2",
+            "y * x + i",
+            "x = y + 1",
+            "f(x) = x * k",
+            "max(a, i)",
+        ] {
+            let expr = parse(input).unwrap();
+            for name in ["x", "y", "i", "k"] {
+                assert_eq!(
+                    expr.reads(name),
+                    expr.vars().contains(name),
+                    "{input}: {name}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn deep_trees_do_not_overflow() {
+        let input = vec!["1"; 200_000].join("+") + "+x";
+        let expr = parse(&input).unwrap();
+        assert_eq!(vars(&input), ["x"]);
+        assert!(!expr.is_constant());
+        assert!(parse(&input.replace('x', "1")).unwrap().is_constant());
+    }
+
+    #[test]
+    fn reads_does_not_recurse() {
+        let input = vec!["1"; 200_000].join("+") + "+i";
+        let expr = parse(&input).unwrap();
+        assert!(expr.reads("i"));
+        assert!(!expr.reads("x"));
+    }
+
+    #[test]
+    fn assignment_targets_and_definition_bodies_are_not_read() {
+        assert_eq!(vars("x = y + 1"), ["y"]);
+        assert_eq!(vars("x = x + 1"), ["x"]);
+        assert!(vars("f(x) = x * k").is_empty());
+    }
+
+    #[test]
+    fn parsed_trees_equal_hand_built_ones() {
+        assert_eq!(
+            parse("1+2").unwrap(),
+            Expr::Add {
+                lhs: Box::new(Expr::Num(1.0)),
+                rhs: Box::new(Expr::Num(2.0)),
+            }
+        );
+        assert_eq!(
+            parse("f(x)").unwrap(),
+            Expr::Call {
+                name: "f".to_owned(),
+                args: vec![Expr::Var("x".to_owned())],
+            }
+        );
+        assert_ne!(parse("1 + 2").unwrap(), parse("2 + 1").unwrap());
+    }
+
+    #[test]
+    fn structural_equality_treats_nans_as_equal() {
+        let nan = parse("nan + 1").unwrap();
+        assert_ne!(nan, nan.clone());
+        assert!(nan.structurally_eq(&nan.clone()));
+        assert!(!nan.structurally_eq(&parse("nan + 2").unwrap()));
+        assert!(!parse("max(1)")
+            .unwrap()
+            .structurally_eq(&parse("min(1)").unwrap()));
+    }
+
+    #[test]
+    fn counts_nodes_and_depth() {
+        let cases = [
+            ("7", 1, 1),
+            ("1 + 2 * 3", 5, 3),
+            ("(1 + 2) * (3 + 4)", 7, 3),
+            ("-x!", 3, 3),
+            ("max(1, 2, 3)", 4, 2),
+            ("x = y ? 1 : 2", 5, 3),
+        ];
+        for (input, count, depth) in cases {
+            let expr = parse(input).unwrap();
+            assert_eq!(expr.count_nodes(), count, "{input}");
+            assert_eq!(expr.depth(), depth, "{input}");
+        }
+    }
+
+    #[test]
+    fn counts_deep_trees_without_recursing() {
+        let mut expr = Expr::Num(1.0);
+        for _ in 0..1_000_000 {
+            expr = Expr::Minus(Box::new(expr));
+        }
+        assert_eq!(expr.count_nodes(), 1_000_001);
+        assert_eq!(expr.depth(), 1_000_001);
+    }
+
+    fn is_constant(input: &str) -> bool {
+        parse(input).unwrap().is_constant()
+    }
+
+    #[test]
+    fn constants_read_nothing_that_can_change() {
+        assert!(is_constant("1 + 2"));
+        assert!(is_constant("sqrt(2)"));
+        assert!(is_constant("max(1, pi) ^ 2 ? 3! : -1"));
+        assert!(!is_constant("x + 1"));
+        assert!(!is_constant("sqrt(x)"));
+        assert!(!is_constant("ans[1]"));
+        assert!(!is_constant("f(2)"));
+        assert!(!is_constant("x = 1"));
+        assert!(!is_constant("f(x) = 2"));
+    }
+
+    fn hash(input: &str) -> u64 {
+        hash_expr(&parse(input).unwrap())
+    }
+
+    #[test]
+    fn equal_trees_hash_equally() {
+        assert_eq!(hash("1 + 2 * x"), hash("1+2*x"));
+        assert_eq!(hash("(1 + 2) * x"), hash("((1 + 2)) * x"));
+        assert_eq!(hash("nan + 1"), hash("nan + 1"));
+        assert_eq!(hash("f(x) = x"), hash("f(x) = x"));
+    }
+
+    #[test]
+    fn different_trees_hash_differently() {
+        assert_ne!(hash("1 + 2"), hash("2 + 1"));
+        assert_ne!(hash("1 + 2"), hash("1 - 2"));
+        assert_ne!(hash("x"), hash("y"));
+        assert_ne!(hash("max(1, 2)"), hash("min(1, 2)"));
+        assert_ne!(hash("max(1)"), hash("max(1, 1)"));
+        assert_ne!(hash("-x"), hash("+x"));
+    }
+
+    fn hash_expr(expr: &Expr) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        expr.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    #[test]
+    fn negative_zero_hashes_like_zero() {
+        assert_eq!(Expr::Num(0.0), Expr::Num(-0.0));
+        assert_eq!(hash_expr(&Expr::Num(0.0)), hash_expr(&Expr::Num(-0.0)));
+        assert_eq!(
+            hash_expr(&Expr::Num(f64::NAN)),
+            hash_expr(&Expr::Num(-f64::NAN))
+        );
+    }
+
+    #[test]
+    fn trees_are_map_keys() {
+        let key = |input: &str| ExprKey(parse(input).unwrap());
+        let mut cache = HashMap::new();
+        cache.insert(key("x * 2"), 1);
+        cache.insert(key("2 * x"), 2);
+        assert_eq!(cache.get(&key("x*2")), Some(&1));
+        assert_eq!(cache.get(&key("2*x")), Some(&2));
+        assert_eq!(cache.get(&key("1 + 1")), None);
+        cache.insert(key("nan + 1"), 3);
+        assert_eq!(cache.get(&key("nan + 1")), Some(&3));
+        cache.insert(ExprKey(Expr::Num(0.0)), 4);
+        assert_eq!(cache.get(&ExprKey(Expr::Num(-0.0))), Some(&4));
+    }
+
+    #[test]
+    fn keys_are_reflexive() {
+        for input in ["nan", "inf - nan * x", "0", "f(x) = x"] {
+            let key = ExprKey(parse(input).unwrap());
+            assert!(key == key.clone(), "{input}");
+        }
+        let key = ExprKey(Expr::Num(1.0));
+        assert!(key != ExprKey(Expr::Num(2.0)));
+        assert!(key != ExprKey(Expr::Var("x".to_owned())));
+    }
+}
@@ -0,0 +1,43 @@
+mod complex;
+#[cfg(feature = "bigdecimal")]
+mod decimal;
+mod derivative;
+mod display;
+pub mod error;
+mod eval;
+mod format;
+mod inspect;
+mod memo;
+pub mod parser;
+mod simplify;
+#[cfg(feature = "units")]
+mod units;
+mod value;
+mod visit;
+
+#[cfg(feature = "bigdecimal")]
+pub use bigdecimal::BigDecimal;
+pub use complex::Complex;
+pub use display::{MulSymbol, PrettyConfig};
+pub use error::{render_error, Diagnostic, Error, Result};
+pub use eval::{AngleMode, DecimalSeparator, Environment, EvalConfig, IntOverflow};
+pub use format::{format_result, FormatConfig, Notation};
+pub use inspect::ExprKey;
+#[cfg(feature = "units")]
+pub use units::Quantity;
+pub use value::Value;
+pub use visit::Visitor;
+
+pub fn eval_str(input: &str) -> Result<f64> {
+    parser::parse(input)?.eval()
+}
+
+// Evaluates each `;`-separated statement in turn, sharing variables between
+// them, so `x = 2; x * 3` gives `[2, 6]`.
+pub fn eval_program(input: &str) -> Result<Vec<f64>> {
+    let mut env = Environment::new();
+    parser::parse_program(input)?
+        .iter()
+        .map(|expr| expr.eval_in(&mut env))
+        .collect()
+}
@@ -1,30 +1,372 @@
-mod error;
-mod parser;
+use std::{env, fs, path::PathBuf, process::ExitCode};
 
-use std::{io::Write, panic::catch_unwind};
+use command::{Command, HELP};
+use rust_calculator::{parser, render_error, Environment, Error, EvalConfig, FormatConfig};
+use rustyline::{error::ReadlineError, DefaultEditor};
 
-fn main() {
-    let stdin = std::io::stdin();
-    let mut stdout = std::io::stdout();
-    let mut buf = String::new();
-    let mut i = 1;
-    loop {
-        buf.clear();
-        print!(">>> ");
-        stdout.flush().unwrap();
-        stdin.read_line(&mut buf).unwrap();
-        let expr = catch_unwind(|| parser::parse(&buf));
-        if expr.is_err() {
-            eprintln!("ERROR: Exception in parser");
+mod command;
+
+fn main() -> ExitCode {
+    let mut quiet = false;
+    let mut file = None;
+    let mut args = Vec::new();
+    let mut argv = env::args().skip(1);
+    while let Some(arg) = argv.next() {
+        if !is_option(&arg) {
+            args.push(arg);
+            continue;
+        }
+        match arg.as_str() {
+            // Drops the prompt and the `[n]:` prefix so output can be piped.
+            "--quiet" => quiet = true,
+            "--file" => match argv.next() {
+                Some(path) => file = Some(path),
+                None => {
+                    eprintln!("ERROR: --file needs a path");
+                    return ExitCode::FAILURE;
+                }
+            },
+            _ => {
+                eprintln!("ERROR: Unknown option {arg:?}");
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+    if let Some(path) = file {
+        if !args.is_empty() {
+            eprintln!("ERROR: --file takes no expression");
+            return ExitCode::FAILURE;
+        }
+        eval_file(&path, quiet)
+    } else if args.is_empty() {
+        repl(quiet)
+    } else {
+        // Arguments are joined so that `rustcalculator 1 + 2` works unquoted.
+        eval_once(&args.join(" "))
+    }
+}
+
+// `--5` is an expression with chained signs, not an option.
+fn is_option(arg: &str) -> bool {
+    arg.strip_prefix("--")
+        .and_then(|name| name.chars().next())
+        .is_some_and(|c| c.is_ascii_alphabetic())
+}
+
+// Exits with 0 on success, 2 if the input does not parse and 3 if it fails
+// to evaluate, as `1/0` does, or gives NaN, as `0*inf` does. An unknown
+// option exits with 1.
+fn eval_once(input: &str) -> ExitCode {
+    let config = EvalConfig {
+        error_on_nan: true,
+        ..EvalConfig::default()
+    };
+    let mut env = Environment::with_config(config);
+    let format = FormatConfig::default();
+    match parser::parse(input).and_then(|expr| evaluate(&expr, &mut env, &format)) {
+        Ok((result, _)) => {
+            println!("{result}");
+            ExitCode::SUCCESS
+        }
+        Err(err) => {
+            eprintln!("ERROR: {}", render_error(input, &err));
+            exit_code(&err)
+        }
+    }
+}
+
+// Evaluates every line of the file in one environment, so later lines see
+// the variables and functions of earlier ones. Blank lines and `#` comments
+// are skipped, and a line that fails is reported with its line number
+// without stopping the rest. Results are numbered as in the REPL, so `[n]`
+// is the result that `ans[n]` reads; only errors give the line number.
+// Exits as `eval_once` does for the first line that failed.
+fn eval_file(path: &str, quiet: bool) -> ExitCode {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(err) => {
+            eprintln!("ERROR: Could not read {path:?}: {err}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let mut env = Environment::new();
+    let format = FormatConfig::default();
+    let mut code = ExitCode::SUCCESS;
+    // How many results there have been, including those of earlier lines.
+    let mut count = 0;
+    for (i, line) in contents.lines().enumerate() {
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
             continue;
         }
-        let expr = expr.unwrap();
-        if let Err(err) = expr {
+        let result = parser::parse_program(line).and_then(|program| {
+            let mut results = Vec::new();
+            for expr in program {
+                let (result, value) = evaluate(&expr, &mut env, &format)?;
+                if !matches!(expr, parser::Expr::Define { .. }) {
+                    env.set("ans", value);
+                    env.push_result(value);
+                    count += 1;
+                    results.push((count, result));
+                }
+            }
+            Ok(results)
+        });
+        match result {
+            Ok(results) => {
+                for (n, result) in results {
+                    if quiet {
+                        println!("{result}");
+                    } else {
+                        println!("[{n}]: {result}");
+                    }
+                }
+            }
+            Err(err) => {
+                eprintln!("ERROR: line {}: {}", i + 1, render_error(line, &err));
+                if code == ExitCode::SUCCESS {
+                    code = exit_code(&err);
+                }
+            }
+        }
+    }
+    code
+}
+
+// Evaluates one statement, returning the result as printed together with
+// the plain number that `ans` and `ans[n]` read back. A statement using `i`
+// when no variable `i` is defined is evaluated over the complex numbers, so
+// `(1 + 2i)^2` prints `-3 + 4i`. Variables hold only reals, so a result with
+// an imaginary part is stored as NaN.
+fn evaluate(
+    expr: &parser::Expr,
+    env: &mut Environment,
+    format: &FormatConfig,
+) -> Result<(String, f64), Error> {
+    if env.get("i").is_some() || !expr.reads("i") {
+        return evaluate_real(expr, env, format);
+    }
+    let value = expr.eval_complex_in(env)?;
+    let real = if value.is_real() { value.re } else { f64::NAN };
+    Ok((format.format_complex(value), real))
+}
+
+#[cfg(not(any(feature = "bigdecimal", feature = "units")))]
+fn evaluate_real(
+    expr: &parser::Expr,
+    env: &mut Environment,
+    format: &FormatConfig,
+) -> Result<(String, f64), Error> {
+    let value = expr.eval_value_in(env)?;
+    Ok((format.format(value), value.into()))
+}
+
+// Built with `bigdecimal`, arithmetic on the parsed `f64` literals is carried
+// out in decimals, so `0.1 + 0.2` prints `0.3` and `0.1 + 0.2 == 0.3` is 1.
+// Literals and variables are still `f64`; see `Expr::eval_decimal_ops`.
+#[cfg(all(feature = "bigdecimal", not(feature = "units")))]
+fn evaluate_real(
+    expr: &parser::Expr,
+    env: &mut Environment,
+    format: &FormatConfig,
+) -> Result<(String, f64), Error> {
+    use bigdecimal::ToPrimitive;
+
+    let value = expr.eval_decimal_ops_in(env)?;
+    let float = value.to_f64().unwrap_or(f64::NAN);
+    Ok((format.format_decimal(&value), float))
+}
+
+// Built with `units`, which takes precedence over `bigdecimal`, a unit name
+// that is not a variable is one of that unit, so `3m + 50cm` prints `3.5 m`.
+// `ans` holds the value in SI base units without them.
+#[cfg(feature = "units")]
+fn evaluate_real(
+    expr: &parser::Expr,
+    env: &mut Environment,
+    format: &FormatConfig,
+) -> Result<(String, f64), Error> {
+    let value = expr.eval_quantity_in(env)?;
+    Ok((format.format_quantity(value), value.value))
+}
+
+fn exit_code(err: &Error) -> ExitCode {
+    match err {
+        Error::InvalidBinOp(_)
+        | Error::MissingOperator(_)
+        | Error::InvalidUnaryOp(_)
+        | Error::UnexpectedEof(_)
+        | Error::InvalidIdent(_)
+        | Error::InvalidNumber(..)
+        | Error::MalformedNumber(..)
+        | Error::UnclosedParen(_)
+        | Error::UnexpectedRightParen(_)
+        | Error::UnclosedBar(_)
+        | Error::ExpressionTooDeep(_)
+        | Error::RpnUnderflow(_)
+        | Error::RpnExtraOperands(_)
+        | Error::InvalidRpnToken(_)
+        | Error::UnexpectedChar(..)
+        | Error::EmptyExpression() => ExitCode::from(2),
+        _ => ExitCode::from(3),
+    }
+}
+
+fn repl(quiet: bool) -> ExitCode {
+    let mut editor = match DefaultEditor::new() {
+        Ok(editor) => editor,
+        Err(err) => {
             eprintln!("ERROR: {err}");
+            return ExitCode::FAILURE;
+        }
+    };
+    // Piped input, which is what `--quiet` is for, stays out of the history.
+    let history = if quiet { None } else { history_path() };
+    if let Some(path) = &history {
+        // A missing history file just means this is the first session.
+        let _ = editor.load_history(path);
+    }
+    let (prompt, output) = prompt_and_output(quiet, |name| env::var(name).ok());
+    let mut i = 1;
+    let mut env = Environment::new();
+    let mut format = FormatConfig::default();
+    loop {
+        let buf = match editor.readline(&prompt) {
+            Ok(buf) => buf,
+            // Ctrl-C abandons the line being typed.
+            Err(ReadlineError::Interrupted) => continue,
+            // End of input. The newline keeps the shell prompt off the
+            // `>>> ` line.
+            Err(ReadlineError::Eof) => {
+                if !quiet {
+                    println!();
+                }
+                break;
+            }
+            Err(err) => {
+                eprintln!("ERROR: {err}");
+                return ExitCode::FAILURE;
+            }
+        };
+        if history.is_some() && !buf.trim().is_empty() {
+            let _ = editor.add_history_entry(buf.as_str());
+        }
+        match Command::parse(&buf) {
+            Some(Ok(Command::Quit)) => break,
+            Some(Ok(command)) => {
+                run_command(command, &mut env, &mut format);
+                continue;
+            }
+            Some(Err(err)) => {
+                eprintln!("ERROR: {err}");
+                continue;
+            }
+            None => {}
+        }
+        let program = parser::parse_program(&buf);
+        if let Err(err) = program {
+            eprintln!("ERROR: {}", render_error(&buf, &err));
             continue;
         }
-        let result = expr.unwrap().eval();
-        println!("[{i}]: {result}");
-        i += 1;
+        // Statements on one line run in order; an error skips the rest.
+        for expr in program.unwrap() {
+            let result = evaluate(&expr, &mut env, &format);
+            if let Err(err) = result {
+                eprintln!("ERROR: {err}");
+                break;
+            }
+            // A definition has no result worth printing or numbering.
+            if matches!(expr, parser::Expr::Define { .. }) {
+                continue;
+            }
+            let (result, value) = result.unwrap();
+            // `ans` only exists once something has been evaluated, so using it
+            // on the first line reports an undefined variable.
+            env.set("ans", value);
+            env.push_result(value);
+            println!(
+                "{}",
+                output
+                    .replace("{i}", &i.to_string())
+                    .replace("{result}", &result)
+            );
+            i += 1;
+        }
+    }
+    if let Some(path) = &history {
+        if let Err(err) = editor.save_history(path) {
+            eprintln!("ERROR: Could not save history: {err}");
+        }
+    }
+    ExitCode::SUCCESS
+}
+
+// `RUSTCALC_PROMPT` replaces the `>>> ` prompt and `RUSTCALC_OUTPUT` the
+// `[{i}]: {result}` line printed for each result, with `{i}` and `{result}`
+// filled in. `--quiet` ignores both. `var` looks up environment variables.
+fn prompt_and_output(quiet: bool, var: impl Fn(&str) -> Option<String>) -> (String, String) {
+    if quiet {
+        return (String::new(), "{result}".to_owned());
+    }
+    (
+        var("RUSTCALC_PROMPT").unwrap_or_else(|| ">>> ".to_owned()),
+        var("RUSTCALC_OUTPUT").unwrap_or_else(|| "[{i}]: {result}".to_owned()),
+    )
+}
+
+fn history_path() -> Option<PathBuf> {
+    let home = env::var_os("HOME")?;
+    Some(PathBuf::from(home).join(".rustcalculator_history"))
+}
+
+fn run_command(command: Command, env: &mut Environment, format: &mut FormatConfig) {
+    match command {
+        Command::Help => println!("{HELP}"),
+        Command::Quit => unreachable!("the REPL loop handles :quit"),
+        Command::Vars => {
+            let mut vars: Vec<_> = env.vars().collect();
+            vars.sort_by_key(|&(name, _)| name);
+            for (name, value) in vars {
+                println!("{name} = {value}");
+            }
+        }
+        Command::Clear => env.clear(),
+        // `:precision 4` rounds results to four decimals, a bare
+        // `:precision` goes back to printing every digit.
+        Command::Precision(precision) => format.precision = precision,
+        Command::Angle(angle_mode) => env.config.angle_mode = angle_mode,
+        Command::Ast(input) => match command::ast(&input) {
+            Ok(tree) => println!("{tree}"),
+            Err(err) => eprintln!("ERROR: {err}"),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prompt_and_output_default() {
+        let (prompt, output) = prompt_and_output(false, |_| None);
+        assert_eq!(prompt, ">>> ");
+        assert_eq!(output, "[{i}]: {result}");
+    }
+
+    #[test]
+    fn prompt_and_output_come_from_the_environment() {
+        let var = |name: &str| match name {
+            "RUSTCALC_PROMPT" => Some("$ ".to_owned()),
+            "RUSTCALC_OUTPUT" => Some("{i} => {result}".to_owned()),
+            _ => None,
+        };
+        assert_eq!(
+            prompt_and_output(false, var),
+            ("$ ".to_owned(), "{i} => {result}".to_owned())
+        );
+        assert_eq!(
+            prompt_and_output(true, var),
+            (String::new(), "{result}".to_owned())
+        );
     }
 }
@@ -0,0 +1,153 @@
+use std::{
+    collections::HashMap,
+    mem::{self, Discriminant},
+};
+
+use crate::{
+    error::Result,
+    eval::{apply, Environment, Frame},
+    parser::Expr,
+};
+
+// A node identified by its operator and the classes of its operands, so two
+// structurally equal subtrees share a key.
+#[derive(PartialEq, Eq, Hash)]
+enum Key<'a> {
+    Num(u64),
+    Var(&'a str),
+    Node(Discriminant<Expr>, Option<&'a str>, Vec<usize>),
+}
+
+impl Expr {
+    // Like `eval_in`, but each distinct subtree is evaluated at most once.
+    // Numbering the subtrees costs a hash lookup per node, so this only beats
+    // `eval_in` when the repeated subtrees are expensive, such as the output
+    // of `derivative` on trig or non-integer factorial terms. Expressions
+    // that assign are evaluated normally, since a repeated subtree can change
+    // value between its occurrences.
+    pub fn eval_memo(&self, env: &mut Environment) -> Result<f64> {
+        let Some(classes) = classes(self) else {
+            return self.eval_in(env);
+        };
+        let mut cache = HashMap::new();
+        let mut frames = vec![Frame::Eval(self)];
+        let mut values = Vec::new();
+        while let Some(frame) = frames.pop() {
+            match frame {
+                Frame::Eval(expr) => {
+                    if let Some(&value) = cache.get(&classes[&ptr(expr)]) {
+                        values.push(value);
+                        continue;
+                    }
+                    frames.push(Frame::Apply(expr));
+                    if let Expr::Cond {
+                        test,
+                        then,
+                        otherwise,
+                    } = expr
+                    {
+                        frames.push(Frame::Branch { then, otherwise });
+                        frames.push(Frame::Eval(test));
+                    } else {
+                        frames.extend(expr.children().into_iter().rev().map(Frame::Eval));
+                    }
+                }
+                Frame::Branch { then, otherwise } => {
+                    if values.pop().unwrap() != 0.0 {
+                        frames.push(Frame::Eval(then));
+                    } else {
+                        frames.push(Frame::Eval(otherwise));
+                    }
+                }
+                Frame::Apply(expr) => {
+                    let value = apply(expr, &mut values, env)?;
+                    cache.insert(classes[&ptr(expr)], value);
+                    values.push(value);
+                }
+            }
+        }
+        Ok(values.pop().unwrap())
+    }
+}
+
+// Numbers every node so that structurally equal subtrees get the same number,
+// or returns `None` if the tree contains an assignment or a definition.
+fn classes(root: &Expr) -> Option<HashMap<*const Expr, usize>> {
+    let mut classes = HashMap::new();
+    let mut keys = HashMap::new();
+    let mut pending = vec![(root, false)];
+    while let Some((expr, visited)) = pending.pop() {
+        if !visited {
+            pending.push((expr, true));
+            pending.extend(expr.children().into_iter().map(|child| (child, false)));
+            continue;
+        }
+        let children = expr
+            .children()
+            .into_iter()
+            .map(|child| classes[&ptr(child)])
+            .collect();
+        let key = match expr {
+            Expr::Num(num) => Key::Num(num.to_bits()),
+            Expr::Var(name) => Key::Var(name),
+            Expr::Assign { .. } | Expr::Define { .. } => return None,
+            Expr::Call { name, .. } => Key::Node(mem::discriminant(expr), Some(name), children),
+            _ => Key::Node(mem::discriminant(expr), None, children),
+        };
+        let next = keys.len();
+        classes.insert(ptr(expr), *keys.entry(key).or_insert(next));
+    }
+    Some(classes)
+}
+
+fn ptr(expr: &Expr) -> *const Expr {
+    expr
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{eval::Environment, parser::parse};
+
+    #[test]
+    fn matches_eval_on_repeated_subtrees() {
+        let mut env = Environment::new();
+        env.set("x", 0.7);
+        let inputs = [
+            "sin(x) * sin(x) + sin(x) * cos(x)",
+            "(x + 1)! / (x + 1)! + 2.5! * 2.5!",
+            "max(x, 1) ^ max(x, 1) - x",
+        ];
+        for input in inputs {
+            let expr = parse(input).unwrap();
+            let expected = expr.eval_in(&mut env).unwrap();
+            assert_eq!(expr.eval_memo(&mut env).unwrap(), expected, "{input}");
+        }
+    }
+
+    #[test]
+    fn matches_eval_on_derivatives() {
+        let mut env = Environment::new();
+        env.set("x", 1.3);
+        let expr = parse("sin(x) ^ 3 / (x ^ 2 + 1)")
+            .unwrap()
+            .derivative("x")
+            .unwrap();
+        let expected = expr.eval_in(&mut env).unwrap();
+        assert_eq!(expr.eval_memo(&mut env).unwrap(), expected);
+    }
+
+    #[test]
+    fn skips_untaken_branches() {
+        let expr = parse("1 ? 2 : 1 / 0").unwrap();
+        assert_eq!(expr.eval_memo(&mut Environment::new()).unwrap(), 2.0);
+    }
+
+    #[test]
+    fn assignments_are_evaluated_in_order() {
+        let expr = parse("(x = x + 1) + (x = x + 1)").unwrap();
+        let mut env = Environment::new();
+        env.set("x", 0.0);
+        assert_eq!(expr.eval_memo(&mut env).unwrap(), 3.0);
+        assert_eq!(env.get("x"), Some(2.0));
+    }
+}
@@ -1,202 +1,1444 @@
-use crate::error::{Error, Result};
+use std::{borrow::Cow, mem};
 
-#[derive(Clone, Copy, Debug, PartialEq)]
+use crate::{
+    error::{Diagnostic, Error, Result},
+    eval::{DecimalSeparator, EvalConfig, FUNCTIONS},
+};
+
+#[derive(Clone, Debug, PartialEq)]
 pub enum Token {
     Num(f64),
+    Ident(String),
     Plus,
     Minus,
     Star,
     Slash,
+    SlashSlash,
+    Percent,
+    Caret,
+    CaretCaret,
+    Amp,
+    Pipe,
+    LessLess,
+    GreaterGreater,
+    Bang,
+    Root,
     LeftParen,
     RightParen,
+    LeftBracket,
+    RightBracket,
+    Comma,
+    Semicolon,
+    Equals,
+    EqualEqual,
+    BangEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    Question,
+    Colon,
+    And,
+    Or,
+    Not,
     Eof,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Assoc {
+    Left,
+    Right,
+}
+
+// Binding strength and associativity of each binary operator; a new operator
+// needs an entry here and an arm in `apply_binary`. Levels follow C.
+// Every operator but `^` is left-associative, so `10 - 2 - 3` is
+// `(10 - 2) - 3`, `100 / 10 / 2` is `(100 / 10) / 2` and `1 < 2 < 3`
+// compares the 0/1 result of `1 < 2` against 3, while `2 ^ 3 ^ 2` is
+// `2 ^ (3 ^ 2)`. The keywords `and` and `or` bind loosest of all. A higher
+// level binds tighter.
+pub const BINARY_OPS: &[(Token, usize, Assoc)] = &[
+    (Token::Or, 1, Assoc::Left),
+    (Token::And, 2, Assoc::Left),
+    (Token::Pipe, 3, Assoc::Left),
+    (Token::CaretCaret, 4, Assoc::Left),
+    (Token::Amp, 5, Assoc::Left),
+    (Token::EqualEqual, 6, Assoc::Left),
+    (Token::BangEqual, 6, Assoc::Left),
+    (Token::Less, 7, Assoc::Left),
+    (Token::LessEqual, 7, Assoc::Left),
+    (Token::Greater, 7, Assoc::Left),
+    (Token::GreaterEqual, 7, Assoc::Left),
+    (Token::LessLess, 8, Assoc::Left),
+    (Token::GreaterGreater, 8, Assoc::Left),
+    (Token::Plus, 9, Assoc::Left),
+    (Token::Minus, 9, Assoc::Left),
+    (Token::Star, 10, Assoc::Left),
+    (Token::Slash, 10, Assoc::Left),
+    (Token::SlashSlash, 10, Assoc::Left),
+    (Token::Percent, 10, Assoc::Left),
+    (Token::Caret, 11, Assoc::Right),
+];
+
+// The operand of `not` extends over every operator that binds tighter than
+// `and`.
+const NOT_PRECEDENCE: usize = 3;
+
+// The operand of a sign takes in `^`, so `-2 ^ 2` is `-(2 ^ 2)`, which is -4,
+// as in written maths. Products stay outside: `-2 * 3` is `(-2) * 3`.
+const SIGN_PRECEDENCE: usize = 11;
+
 impl Token {
     fn is_unary_op(&self) -> bool {
-        matches!(self, Token::Plus | Token::Minus)
+        matches!(self, Token::Plus | Token::Minus | Token::Root | Token::Not)
+    }
+
+    // The binding strength of a binary operator, `None` for any other token.
+    pub fn precedence(&self) -> Option<usize> {
+        self.binary_op().map(|(precedence, _)| precedence)
     }
 
-    fn is_binary_op(&self) -> bool {
+    fn binary_op(&self) -> Option<(usize, Assoc)> {
+        BINARY_OPS
+            .iter()
+            .find(|(op, ..)| op == self)
+            .map(|&(_, precedence, assoc)| (precedence, assoc))
+    }
+
+    fn starts_operand(&self) -> bool {
         matches!(
             self,
-            Token::Plus | Token::Minus | Token::Star | Token::Slash
+            Token::Num(_) | Token::Ident(_) | Token::LeftParen | Token::Not
         )
     }
-
-    fn precedence(&self) -> usize {
-        match self {
-            Token::Plus | Token::Minus => 1,
-            Token::Star | Token::Slash => 2,
-            _ => panic!("Invalid operator"),
-        }
-    }
 }
 
+// Equality compares numbers as f64s, so a tree holding a NaN is not equal to
+// itself; `structurally_eq` treats NaNs as equal. JSON has no infinities or
+// NaN, so `inf` and `nan` do not survive a round trip through serde_json.
+//
+// Parsing, evaluating, printing, comparing, hashing, cloning and dropping a
+// tree never recurse, so they work on any tree `parse` accepts, such as a
+// chain of 200,000 `1+`s. `Debug`, the serde impls, `accept`, `simplify`
+// and `derivative` do recurse, and a tree that deep overflows the stack.
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Expr {
     Num(f64),
+    Var(String),
     Plus(Box<Expr>),
     Minus(Box<Expr>),
-    Add { lhs: Box<Expr>, rhs: Box<Expr> },
-    Sub { lhs: Box<Expr>, rhs: Box<Expr> },
-    Mul { lhs: Box<Expr>, rhs: Box<Expr> },
-    Div { lhs: Box<Expr>, rhs: Box<Expr> },
+    Add {
+        lhs: Box<Expr>,
+        rhs: Box<Expr>,
+    },
+    Sub {
+        lhs: Box<Expr>,
+        rhs: Box<Expr>,
+    },
+    Mul {
+        lhs: Box<Expr>,
+        rhs: Box<Expr>,
+    },
+    Div {
+        lhs: Box<Expr>,
+        rhs: Box<Expr>,
+    },
+    FloorDiv {
+        lhs: Box<Expr>,
+        rhs: Box<Expr>,
+    },
+    Mod {
+        lhs: Box<Expr>,
+        rhs: Box<Expr>,
+    },
+    Pow {
+        base: Box<Expr>,
+        exp: Box<Expr>,
+    },
+    Eq {
+        lhs: Box<Expr>,
+        rhs: Box<Expr>,
+    },
+    Ne {
+        lhs: Box<Expr>,
+        rhs: Box<Expr>,
+    },
+    Lt {
+        lhs: Box<Expr>,
+        rhs: Box<Expr>,
+    },
+    Le {
+        lhs: Box<Expr>,
+        rhs: Box<Expr>,
+    },
+    Gt {
+        lhs: Box<Expr>,
+        rhs: Box<Expr>,
+    },
+    Ge {
+        lhs: Box<Expr>,
+        rhs: Box<Expr>,
+    },
+    BitAnd {
+        lhs: Box<Expr>,
+        rhs: Box<Expr>,
+    },
+    BitOr {
+        lhs: Box<Expr>,
+        rhs: Box<Expr>,
+    },
+    BitXor {
+        lhs: Box<Expr>,
+        rhs: Box<Expr>,
+    },
+    Shl {
+        lhs: Box<Expr>,
+        rhs: Box<Expr>,
+    },
+    Shr {
+        lhs: Box<Expr>,
+        rhs: Box<Expr>,
+    },
+    Factorial(Box<Expr>),
+    Call {
+        name: String,
+        args: Vec<Expr>,
+    },
+    Assign {
+        name: String,
+        value: Box<Expr>,
+    },
+    // `name(param) = body`, which stores the function in the environment.
+    Define {
+        name: String,
+        param: String,
+        body: Box<Expr>,
+    },
+    Cond {
+        test: Box<Expr>,
+        then: Box<Expr>,
+        otherwise: Box<Expr>,
+    },
+}
+
+impl Expr {
+    // The direct operands of the node, left to right.
+    pub(crate) fn children(&self) -> Vec<&Expr> {
+        match self {
+            Expr::Num(_) | Expr::Var(_) => Vec::new(),
+            Expr::Plus(expr)
+            | Expr::Minus(expr)
+            | Expr::Factorial(expr)
+            | Expr::Assign { value: expr, .. }
+            | Expr::Define { body: expr, .. } => vec![expr],
+            Expr::Add { lhs, rhs }
+            | Expr::Sub { lhs, rhs }
+            | Expr::Mul { lhs, rhs }
+            | Expr::Div { lhs, rhs }
+            | Expr::FloorDiv { lhs, rhs }
+            | Expr::Mod { lhs, rhs }
+            | Expr::Eq { lhs, rhs }
+            | Expr::Ne { lhs, rhs }
+            | Expr::Lt { lhs, rhs }
+            | Expr::Le { lhs, rhs }
+            | Expr::Gt { lhs, rhs }
+            | Expr::Ge { lhs, rhs }
+            | Expr::BitAnd { lhs, rhs }
+            | Expr::BitOr { lhs, rhs }
+            | Expr::BitXor { lhs, rhs }
+            | Expr::Shl { lhs, rhs }
+            | Expr::Shr { lhs, rhs }
+            | Expr::Pow {
+                base: lhs,
+                exp: rhs,
+            } => vec![lhs, rhs],
+            Expr::Call { args, .. } => args.iter().collect(),
+            Expr::Cond {
+                test,
+                then,
+                otherwise,
+            } => vec![test, then, otherwise],
+        }
+    }
+
+    pub(crate) fn children_mut(&mut self) -> Vec<&mut Expr> {
+        match self {
+            Expr::Num(_) | Expr::Var(_) => Vec::new(),
+            Expr::Plus(expr)
+            | Expr::Minus(expr)
+            | Expr::Factorial(expr)
+            | Expr::Assign { value: expr, .. }
+            | Expr::Define { body: expr, .. } => vec![expr],
+            Expr::Add { lhs, rhs }
+            | Expr::Sub { lhs, rhs }
+            | Expr::Mul { lhs, rhs }
+            | Expr::Div { lhs, rhs }
+            | Expr::FloorDiv { lhs, rhs }
+            | Expr::Mod { lhs, rhs }
+            | Expr::Eq { lhs, rhs }
+            | Expr::Ne { lhs, rhs }
+            | Expr::Lt { lhs, rhs }
+            | Expr::Le { lhs, rhs }
+            | Expr::Gt { lhs, rhs }
+            | Expr::Ge { lhs, rhs }
+            | Expr::BitAnd { lhs, rhs }
+            | Expr::BitOr { lhs, rhs }
+            | Expr::BitXor { lhs, rhs }
+            | Expr::Shl { lhs, rhs }
+            | Expr::Shr { lhs, rhs }
+            | Expr::Pow {
+                base: lhs,
+                exp: rhs,
+            } => vec![lhs, rhs],
+            Expr::Call { args, .. } => args.iter_mut().collect(),
+            Expr::Cond {
+                test,
+                then,
+                otherwise,
+            } => vec![test, then, otherwise],
+        }
+    }
+}
+
+// Copies the tree node by node, as the derived clone would but without
+// recursing.
+impl Clone for Expr {
+    fn clone(&self) -> Self {
+        let mut root = self.shell();
+        let mut pending = vec![(self, &mut root)];
+        while let Some((expr, copy)) = pending.pop() {
+            for (child, slot) in expr.children().into_iter().zip(copy.children_mut()) {
+                *slot = child.shell();
+                pending.push((child, slot));
+            }
+        }
+        root
+    }
 }
 
 impl Expr {
-    pub fn eval(&self) -> f64 {
+    // A copy of the node alone, with placeholders for its children.
+    fn shell(&self) -> Expr {
+        let leaf = || Box::new(Expr::Num(0.0));
         match self {
-            Expr::Num(num) => *num,
-            Expr::Plus(expr) => expr.eval(),
-            Expr::Minus(expr) => -expr.eval(),
-            Expr::Add { lhs, rhs } => lhs.eval() + rhs.eval(),
-            Expr::Sub { lhs, rhs } => lhs.eval() - rhs.eval(),
-            Expr::Mul { lhs, rhs } => lhs.eval() * rhs.eval(),
-            Expr::Div { lhs, rhs } => lhs.eval() / rhs.eval(),
+            Expr::Num(num) => Expr::Num(*num),
+            Expr::Var(name) => Expr::Var(name.clone()),
+            Expr::Plus(_) => Expr::Plus(leaf()),
+            Expr::Minus(_) => Expr::Minus(leaf()),
+            Expr::Factorial(_) => Expr::Factorial(leaf()),
+            Expr::Add { .. } => Expr::Add {
+                lhs: leaf(),
+                rhs: leaf(),
+            },
+            Expr::Sub { .. } => Expr::Sub {
+                lhs: leaf(),
+                rhs: leaf(),
+            },
+            Expr::Mul { .. } => Expr::Mul {
+                lhs: leaf(),
+                rhs: leaf(),
+            },
+            Expr::Div { .. } => Expr::Div {
+                lhs: leaf(),
+                rhs: leaf(),
+            },
+            Expr::FloorDiv { .. } => Expr::FloorDiv {
+                lhs: leaf(),
+                rhs: leaf(),
+            },
+            Expr::Mod { .. } => Expr::Mod {
+                lhs: leaf(),
+                rhs: leaf(),
+            },
+            Expr::Pow { .. } => Expr::Pow {
+                base: leaf(),
+                exp: leaf(),
+            },
+            Expr::Eq { .. } => Expr::Eq {
+                lhs: leaf(),
+                rhs: leaf(),
+            },
+            Expr::Ne { .. } => Expr::Ne {
+                lhs: leaf(),
+                rhs: leaf(),
+            },
+            Expr::Lt { .. } => Expr::Lt {
+                lhs: leaf(),
+                rhs: leaf(),
+            },
+            Expr::Le { .. } => Expr::Le {
+                lhs: leaf(),
+                rhs: leaf(),
+            },
+            Expr::Gt { .. } => Expr::Gt {
+                lhs: leaf(),
+                rhs: leaf(),
+            },
+            Expr::Ge { .. } => Expr::Ge {
+                lhs: leaf(),
+                rhs: leaf(),
+            },
+            Expr::BitAnd { .. } => Expr::BitAnd {
+                lhs: leaf(),
+                rhs: leaf(),
+            },
+            Expr::BitOr { .. } => Expr::BitOr {
+                lhs: leaf(),
+                rhs: leaf(),
+            },
+            Expr::BitXor { .. } => Expr::BitXor {
+                lhs: leaf(),
+                rhs: leaf(),
+            },
+            Expr::Shl { .. } => Expr::Shl {
+                lhs: leaf(),
+                rhs: leaf(),
+            },
+            Expr::Shr { .. } => Expr::Shr {
+                lhs: leaf(),
+                rhs: leaf(),
+            },
+            Expr::Call { name, args } => Expr::Call {
+                name: name.clone(),
+                args: args.iter().map(|_| Expr::Num(0.0)).collect(),
+            },
+            Expr::Assign { name, .. } => Expr::Assign {
+                name: name.clone(),
+                value: leaf(),
+            },
+            Expr::Define { name, param, .. } => Expr::Define {
+                name: name.clone(),
+                param: param.clone(),
+                body: leaf(),
+            },
+            Expr::Cond { .. } => Expr::Cond {
+                test: leaf(),
+                then: leaf(),
+                otherwise: leaf(),
+            },
+        }
+    }
+}
+
+// The derived drop would recurse once per level, so dropping a long chain
+// like `1+1+…+1` would overflow the stack even though parsing and evaluating
+// it do not. Children are moved onto a stack and dropped one at a time.
+impl Drop for Expr {
+    fn drop(&mut self) {
+        let mut pending = Vec::new();
+        take_branches(self, &mut pending);
+        while let Some(mut expr) = pending.pop() {
+            take_branches(&mut expr, &mut pending);
+            // Only leaves are left below `expr`, so dropping it ends here.
+        }
+    }
+}
+
+// Moves every child that has children of its own onto `pending`.
+fn take_branches(expr: &mut Expr, pending: &mut Vec<Expr>) {
+    if matches!(expr, Expr::Num(_) | Expr::Var(_)) {
+        return;
+    }
+    for child in expr.children_mut() {
+        if !matches!(child, Expr::Num(_) | Expr::Var(_)) {
+            pending.push(mem::replace(child, Expr::Num(0.0)));
         }
     }
 }
 
+const CONSTANTS: &[(&str, f64)] = &[
+    ("inf", f64::INFINITY),
+    ("nan", f64::NAN),
+    ("pi", std::f64::consts::PI),
+    ("e", std::f64::consts::E),
+    ("tau", std::f64::consts::TAU),
+    // The golden ratio, (1 + √5) / 2.
+    ("phi", 1.618_033_988_749_895),
+];
+
+fn constant(name: &str) -> Option<f64> {
+    CONSTANTS
+        .iter()
+        .find(|(constant, _)| *constant == name)
+        .map(|(_, value)| *value)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+// How deeply `parse` lets parentheses, bars, calls, signs and `^` chains nest
+// before giving up with `Error::ExpressionTooDeep`, well short of overflowing
+// the stack.
+pub const DEFAULT_MAX_DEPTH: usize = 256;
+
 pub fn parse(input: &str) -> Result<Expr> {
-    let mut chars = input.chars().peekable();
-    let mut tokens = Vec::new();
-    while let Some(c) = chars.next() {
-        match c {
-            '(' => {
-                tokens.push(Token::LeftParen);
+    parse_with_max_depth(input, DEFAULT_MAX_DEPTH)
+}
+
+pub fn parse_with_max_depth(input: &str, max_depth: usize) -> Result<Expr> {
+    prat(&tokenize_spanned(input)?, max_depth)
+}
+
+// Parses characters as they arrive, such as those decoded from a reader,
+// without first collecting them into a `String`. The tokens are still
+// buffered, since parentheses are matched before parsing starts.
+pub fn parse_chars(chars: impl Iterator<Item = char>) -> Result<Expr> {
+    let tokens: Result<Vec<_>> = Lexer::new(chars).collect();
+    prat(&tokens?, DEFAULT_MAX_DEPTH)
+}
+
+// Parses tokens that did not come from text, such as those from a keypad.
+// They are read as if written without spaces between them, so
+// `[Num(50.0), Percent]` is a percent sign, and the spans in errors count
+// tokens rather than bytes. The trailing `Eof` may be left out; one anywhere
+// else is an error.
+pub fn parse_tokens(tokens: &[Token]) -> Result<Expr> {
+    let mut spanned: Vec<(Token, Span)> = tokens
+        .iter()
+        .enumerate()
+        .map(|(i, token)| (token.clone(), Span::new(i, 1)))
+        .collect();
+    if spanned.last().map(|(token, _)| token) == Some(&Token::Eof) {
+        spanned.pop();
+    }
+    if let Some((_, span)) = spanned.iter().find(|(token, _)| *token == Token::Eof) {
+        return Err(Error::InvalidBinOp(*span));
+    }
+    spanned.push((Token::Eof, Span::new(spanned.len(), 0)));
+    prat(&spanned, DEFAULT_MAX_DEPTH)
+}
+
+// Like `parse`, but also reports how the parser read ambiguous input, such
+// as the multiplication it inserted in `2pi`. Diagnostics found before a
+// parse error are returned alongside it.
+pub fn parse_with_diagnostics(input: &str) -> (Result<Expr>, Vec<Diagnostic>) {
+    match tokenize_spanned(input) {
+        Ok(tokens) => prat_diagnosed(&tokens, DEFAULT_MAX_DEPTH),
+        Err(err) => (Err(err), Vec::new()),
+    }
+}
+
+// Parses numbers with the configured decimal separator.
+pub fn parse_with_config(input: &str, config: &EvalConfig) -> Result<Expr> {
+    let tokens: Result<Vec<_>> = lex_str(input, config.decimal_separator).collect();
+    prat(&tokens?, DEFAULT_MAX_DEPTH)
+}
+
+// Checks that `input` parses, reporting exactly the error `parse` would.
+pub fn validate(input: &str) -> Result<()> {
+    parse(input).map(drop)
+}
+
+// Reads space-separated postfix tokens as written by `Expr::to_rpn`, so
+// `3 4 + 5 *` is `(3 + 4) * 5`. Tokens written flush against each other, such
+// as `-3` or `max/3`, form a single word.
+pub fn parse_rpn(input: &str) -> Result<Expr> {
+    let tokens = tokenize_spanned(input)?;
+    let mut stack = Vec::new();
+    let mut start = 0;
+    while start < tokens.len() - 1 {
+        let mut end = start + 1;
+        while end < tokens.len() - 1 && tokens[end - 1].1.end == tokens[end].1.start {
+            end += 1;
+        }
+        let word = &tokens[start..end];
+        let span = Span {
+            start: word[0].1.start,
+            end: word[word.len() - 1].1.end,
+        };
+        rpn_word(&mut stack, word, span)?;
+        start = end;
+    }
+    match (stack.pop(), stack.len()) {
+        (None, _) => Err(Error::EmptyExpression()),
+        (Some(expr), 0) => Ok(expr),
+        (Some(_), extra) => Err(Error::RpnExtraOperands(extra)),
+    }
+}
+
+fn rpn_word(stack: &mut Vec<Expr>, word: &[(Token, Span)], span: Span) -> Result<()> {
+    let tokens: Vec<&Token> = word.iter().map(|(token, _)| token).collect();
+    let operands = match tokens[..] {
+        // Signs are spelled `pos` and `neg` so that `+` and `-` stay binary.
+        [Token::Ident(name)] if name == "pos" || name == "neg" => 1,
+        [Token::Num(_) | Token::Ident(_)] | [Token::Minus, Token::Num(_)] => 0,
+        [Token::Ident(name), Token::Slash, Token::Num(argc)] if argc.fract() == 0.0 => {
+            *argc as usize
+        }
+        // The `f(x)` that a definition starts with.
+        [Token::Ident(_), Token::LeftParen, Token::Ident(_), Token::RightParen] => 0,
+        [Token::Root | Token::Bang | Token::Not] => 1,
+        [Token::Question] => 3,
+        [op] if *op == Token::Equals || op.binary_op().is_some() => 2,
+        _ => return Err(Error::InvalidRpnToken(span)),
+    };
+    if stack.len() < operands {
+        return Err(Error::RpnUnderflow(span));
+    }
+    let mut args = stack.split_off(stack.len() - operands).into_iter();
+    let mut arg = || args.next().expect("operands were counted above");
+    let expr = match tokens[..] {
+        [Token::Num(num)] => Expr::Num(*num),
+        [Token::Minus, Token::Num(num)] => Expr::Num(-num),
+        [Token::Ident(name)] if name == "pos" => apply_unary(&Token::Plus, arg(), span)?,
+        [Token::Ident(name)] if name == "neg" => apply_unary(&Token::Minus, arg(), span)?,
+        [Token::Ident(name)] => Expr::Var(name.clone()),
+        [Token::Ident(name), Token::LeftParen, Token::Ident(param), Token::RightParen] => {
+            Expr::Call {
+                name: name.clone(),
+                args: vec![Expr::Var(param.clone())],
+            }
+        }
+        [Token::Ident(name), ..] => Expr::Call {
+            name: name.clone(),
+            args: std::iter::from_fn(|| Some(arg())).take(operands).collect(),
+        },
+        [Token::Root | Token::Not] => apply_unary(tokens[0], arg(), span)?,
+        [Token::Bang] => Expr::Factorial(Box::new(arg())),
+        [Token::Question] => Expr::Cond {
+            test: Box::new(arg()),
+            then: Box::new(arg()),
+            otherwise: Box::new(arg()),
+        },
+        [Token::Equals] => {
+            let (mut target, value) = (arg(), arg());
+            match &mut target {
+                Expr::Var(name) => Expr::Assign {
+                    name: mem::take(name),
+                    value: Box::new(value),
+                },
+                Expr::Call { name, args } => match &mut args[..] {
+                    [Expr::Var(param)] => define(mem::take(name), mem::take(param), value, span)?,
+                    _ => return Err(Error::InvalidIdent(span)),
+                },
+                _ => return Err(Error::InvalidIdent(span)),
             }
-            ')' => {
-                tokens.push(Token::RightParen);
+        }
+        [op] => {
+            let lhs = arg();
+            apply_binary(op, lhs, arg(), span)?
+        }
+        _ => return Err(Error::InvalidRpnToken(span)),
+    };
+    stack.push(expr);
+    Ok(())
+}
+
+// Statements are separated by `;`. Empty statements, such as the one after a
+// trailing `;`, are skipped.
+pub fn parse_program(input: &str) -> Result<Vec<Expr>> {
+    let tokens = tokenize_spanned(input)?;
+    check_parens(&tokens, DEFAULT_MAX_DEPTH)?;
+    let mut state = State::new(&tokens, DEFAULT_MAX_DEPTH);
+    let mut program = Vec::new();
+    loop {
+        match state.peek() {
+            Token::Eof => return Ok(program),
+            Token::Semicolon => state.eat(),
+            _ => program.push(parse_expr(&mut state, &[Token::Semicolon, Token::Eof])?),
+        }
+    }
+}
+
+pub fn tokenize(input: &str) -> Result<Vec<Token>> {
+    let tokens = tokenize_spanned(input)?;
+    Ok(tokens.into_iter().map(|(token, _)| token).collect())
+}
+
+pub fn tokenize_spanned(input: &str) -> Result<Vec<(Token, Span)>> {
+    lex_str(input, DecimalSeparator::Dot).collect()
+}
+
+// Collects every lexing error instead of stopping at the first one, and only
+// parses when the input lexed cleanly.
+pub fn parse_collect(input: &str) -> (Option<Expr>, Vec<Error>) {
+    let mut tokens = Vec::new();
+    let mut errors = Vec::new();
+    for token in lex_str(input, DecimalSeparator::Dot) {
+        match token {
+            Ok(token) => tokens.push(token),
+            Err(err) => errors.push(err),
+        }
+    }
+    if !errors.is_empty() {
+        return (None, errors);
+    }
+    match prat(&tokens, DEFAULT_MAX_DEPTH) {
+        Ok(expr) => (Some(expr), errors),
+        Err(err) => {
+            errors.push(err);
+            (None, errors)
+        }
+    }
+}
+
+// Yields tokens up to and including `Eof`, reading characters only as it
+// needs them. A bad character or number yields an error and lexing resumes
+// right after it. Spans are byte offsets into the text the characters spell.
+pub struct Lexer<I: Iterator<Item = char>>(Tokens<Chars<I>>);
+
+impl<I: Iterator<Item = char>> Lexer<I> {
+    pub fn new(chars: I) -> Self {
+        Self::with_separator(chars, DecimalSeparator::Dot)
+    }
+
+    pub fn with_separator(chars: I, separator: DecimalSeparator) -> Self {
+        let chars = Chars {
+            chars,
+            pos: 0,
+            peeked: None,
+            text: String::new(),
+            text_start: 0,
+        };
+        Lexer(Tokens::new(chars, separator))
+    }
+}
+
+impl<I: Iterator<Item = char>> Iterator for Lexer<I> {
+    type Item = Result<(Token, Span)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next()
+    }
+}
+
+// Lexes a string in place: the text of numbers and identifiers is sliced
+// out of `input` rather than copied character by character.
+fn lex_str(input: &str, separator: DecimalSeparator) -> Tokens<StrChars<'_>> {
+    Tokens::new(StrChars { input, pos: 0 }, separator)
+}
+
+struct Tokens<S> {
+    chars: S,
+    decimal: char,
+    done: bool,
+}
+
+impl<S: Source> Tokens<S> {
+    fn new(chars: S, separator: DecimalSeparator) -> Self {
+        Tokens {
+            chars,
+            decimal: separator.as_char(),
+            done: false,
+        }
+    }
+}
+
+impl<S: Source> Iterator for Tokens<S> {
+    type Item = Result<(Token, Span)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        loop {
+            self.chars.mark();
+            let Some((start, c)) = self.chars.next() else {
+                self.done = true;
+                return Some(Ok((Token::Eof, Span::new(self.chars.offset(), 0))));
+            };
+            let token = match lex_token(&mut self.chars, start, c, self.decimal) {
+                Ok(Some(token)) => token,
+                Ok(None) => continue,
+                Err(err) => return Some(Err(err)),
+            };
+            let end = self.chars.offset();
+            return Some(Ok((token, Span { start, end })));
+        }
+    }
+}
+
+// Characters paired with their byte offsets, as `Peekable<CharIndices>`
+// yields them, that can also hand back the text of the current token.
+trait Source {
+    fn next(&mut self) -> Option<(usize, char)>;
+
+    fn peek(&mut self) -> Option<(usize, char)>;
+
+    // The offset of the next character, or the length of the text once every
+    // character has been read.
+    fn offset(&mut self) -> usize;
+
+    // Starts a new token, before its first character is read.
+    fn mark(&mut self);
+
+    // The text read since `mark`, from the token's first character at
+    // `start` up to the next character.
+    fn text(&self, start: usize) -> &str;
+
+    fn next_if(&mut self, pred: impl FnOnce(&(usize, char)) -> bool) -> Option<(usize, char)> {
+        let next = self.peek()?;
+        if pred(&next) {
+            self.next()
+        } else {
+            None
+        }
+    }
+}
+
+struct StrChars<'a> {
+    input: &'a str,
+    // The offset of the next character.
+    pos: usize,
+}
+
+impl Source for StrChars<'_> {
+    fn next(&mut self) -> Option<(usize, char)> {
+        let next = self.peek()?;
+        self.pos += next.1.len_utf8();
+        Some(next)
+    }
+
+    fn peek(&mut self) -> Option<(usize, char)> {
+        let c = self.input[self.pos..].chars().next()?;
+        Some((self.pos, c))
+    }
+
+    fn offset(&mut self) -> usize {
+        self.pos
+    }
+
+    fn mark(&mut self) {}
+
+    fn text(&self, start: usize) -> &str {
+        &self.input[start..self.pos]
+    }
+}
+
+// Any other characters, with the offsets they would have in the equivalent
+// string. The current token's characters are copied into `text` as they are
+// read, since there is no string to slice them from.
+struct Chars<I: Iterator<Item = char>> {
+    chars: I,
+    // The offset of the first character not yet taken from `chars`.
+    pos: usize,
+    peeked: Option<Option<(usize, char)>>,
+    text: String,
+    text_start: usize,
+}
+
+impl<I: Iterator<Item = char>> Source for Chars<I> {
+    fn next(&mut self) -> Option<(usize, char)> {
+        let next = match self.peeked.take() {
+            Some(peeked) => peeked,
+            None => {
+                let c = self.chars.next()?;
+                let start = self.pos;
+                self.pos += c.len_utf8();
+                Some((start, c))
             }
-            '+' => {
-                tokens.push(Token::Plus);
+        };
+        if let Some((start, c)) = next {
+            if self.text.is_empty() {
+                self.text_start = start;
             }
-            '-' => {
-                tokens.push(Token::Minus);
+            self.text.push(c);
+        }
+        next
+    }
+
+    fn peek(&mut self) -> Option<(usize, char)> {
+        if self.peeked.is_none() {
+            let next = self.chars.next().map(|c| {
+                let start = self.pos;
+                self.pos += c.len_utf8();
+                (start, c)
+            });
+            self.peeked = Some(next);
+        }
+        self.peeked.flatten()
+    }
+
+    fn offset(&mut self) -> usize {
+        match self.peek() {
+            Some((offset, _)) => offset,
+            None => self.pos,
+        }
+    }
+
+    fn mark(&mut self) {
+        self.text.clear();
+    }
+
+    fn text(&self, start: usize) -> &str {
+        &self.text[start - self.text_start..]
+    }
+}
+
+fn lex_token(
+    chars: &mut impl Source,
+    start: usize,
+    c: char,
+    decimal: char,
+) -> Result<Option<Token>> {
+    let token = match c {
+        '(' => Token::LeftParen,
+        ')' => Token::RightParen,
+        '[' => Token::LeftBracket,
+        ']' => Token::RightBracket,
+        '+' => Token::Plus,
+        // `−` (U+2212), `×` and `÷` are what math pasted from documents uses.
+        '-' | '−' => Token::Minus,
+        '*' | '×' => Token::Star,
+        '/' if eat_char(chars, '/') => Token::SlashSlash,
+        '/' | '÷' => Token::Slash,
+        '%' => Token::Percent,
+        '^' if eat_char(chars, '^') => Token::CaretCaret,
+        '^' => Token::Caret,
+        '&' => Token::Amp,
+        '|' => Token::Pipe,
+        '√' => Token::Root,
+        // `3!=6` is `3 != 6`; the factorial needs a space, `3! == 6`.
+        '!' if eat_char(chars, '=') => Token::BangEqual,
+        '!' => Token::Bang,
+        ',' if decimal != ',' => Token::Comma,
+        ';' if decimal == ',' => Token::Comma,
+        ';' => Token::Semicolon,
+        '=' if eat_char(chars, '=') => Token::EqualEqual,
+        '=' => Token::Equals,
+        '<' if eat_char(chars, '<') => Token::LessLess,
+        '<' if eat_char(chars, '=') => Token::LessEqual,
+        '<' => Token::Less,
+        '>' if eat_char(chars, '>') => Token::GreaterGreater,
+        '>' if eat_char(chars, '=') => Token::GreaterEqual,
+        '>' => Token::Greater,
+        '?' => Token::Question,
+        ':' => Token::Colon,
+        c if c.is_alphabetic() => {
+            take_while(chars, |c| c.is_alphanumeric() || c == '_');
+            match chars.text(start) {
+                "and" => Token::And,
+                "or" => Token::Or,
+                "not" => Token::Not,
+                name => match constant(name) {
+                    Some(value) => Token::Num(value),
+                    None => Token::Ident(name.to_owned()),
+                },
             }
-            '*' => {
-                tokens.push(Token::Star);
+        }
+        '0' if matches!(chars.peek(), Some((_, 'x' | 'b' | 'o'))) => {
+            take_while(chars, |c| c.is_ascii_alphanumeric() || c == '_');
+            let text = chars.text(start);
+            let radix = match &text[1..2] {
+                "x" => 16,
+                "b" => 2,
+                _ => 8,
+            };
+            let digits = strip_separators(&text[2..], |c| c.is_ascii_alphanumeric());
+            let Some(Ok(num)) = digits.map(|digits| u64::from_str_radix(&digits, radix)) else {
+                let span = Span::new(start, text.len());
+                return Err(Error::InvalidNumber(text.to_owned(), span));
+            };
+            Token::Num(num as f64)
+        }
+        c if c.is_ascii_digit()
+            || c == decimal
+            || c == '_' && matches!(chars.peek(), Some((_, '0'..='9'))) =>
+        {
+            take_while(chars, |c| c.is_ascii_digit() || c == decimal || c == '_');
+            let text = chars.text(start);
+            if text.matches(decimal).count() > 1 || text.len() == 1 && c == decimal {
+                let span = Span::new(start, text.len());
+                return Err(Error::MalformedNumber(text.to_owned(), span));
             }
-            '/' => {
-                tokens.push(Token::Slash);
+            if chars.next_if(|&(_, c)| matches!(c, 'e' | 'E')).is_some() {
+                chars.next_if(|&(_, c)| matches!(c, '+' | '-'));
+                take_while(chars, |c| c.is_ascii_digit() || c == '_');
             }
-            'i' => {
-                if chars.next().unwrap() != 'n' || chars.next().unwrap() != 'f' {
-                    return Err(Error::InvalidIdent());
-                }
-                tokens.push(Token::Num(f64::INFINITY));
-            }
-            c if c.is_ascii_digit() || c == '.' => {
-                let mut buf = String::new();
-                buf.push(c);
-                while let Some(ac) = chars.peek() {
-                    let ac = *ac;
-                    if ac.is_ascii_digit() || ac == '.' {
-                        chars.next();
-                        buf.push(ac);
-                        continue;
-                    }
-                    break;
-                }
-                let Ok(num) = buf.parse() else {
-                    return Err(Error::InvalidNumber(buf));
-                };
-                tokens.push(Token::Num(num));
+            let text = chars.text(start);
+            let digits = strip_separators(text, |c| c.is_ascii_digit());
+            let num = digits.map(|digits| match decimal {
+                '.' => digits.parse(),
+                _ => digits.replace(decimal, ".").parse(),
+            });
+            let Some(Ok(num)) = num else {
+                let span = Span::new(start, text.len());
+                return Err(Error::InvalidNumber(text.to_owned(), span));
+            };
+            Token::Num(num)
+        }
+        c if c.is_whitespace() => return Ok(None),
+        // A comment runs to the end of the line.
+        '#' => {
+            while chars.next_if(|&(_, c)| c != '\n').is_some() {}
+            return Ok(None);
+        }
+        c => return Err(Error::UnexpectedChar(c, Span::new(start, c.len_utf8()))),
+    };
+    Ok(Some(token))
+}
+
+impl Span {
+    fn new(start: usize, len: usize) -> Self {
+        Span {
+            start,
+            end: start + len,
+        }
+    }
+}
+
+// Underscores may group digits, as in `1_000_000`, but only between two
+// digits: `_1`, `1_` and `1__0` are rejected.
+// Only a literal that has separators is copied.
+fn strip_separators(buf: &str, is_digit: impl Fn(char) -> bool) -> Option<Cow<'_, str>> {
+    let mut prev = None;
+    let mut chars = buf.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '_' {
+            let before = prev.is_some_and(&is_digit);
+            let after = chars.peek().is_some_and(|&c| is_digit(c));
+            if !before || !after {
+                return None;
             }
-            c if c.is_whitespace() => {}
-            c => return Err(Error::UnexpectedChar(c)),
         }
+        prev = Some(c);
+    }
+    if buf.contains('_') {
+        Some(Cow::Owned(buf.replace('_', "")))
+    } else {
+        Some(Cow::Borrowed(buf))
     }
-    tokens.push(Token::Eof);
-    prat(&tokens)
+}
+
+fn eat_char(chars: &mut impl Source, expected: char) -> bool {
+    chars.next_if(|&(_, c)| c == expected).is_some()
+}
+
+fn take_while(chars: &mut impl Source, pred: impl Fn(char) -> bool) {
+    while chars.next_if(|&(_, c)| pred(c)).is_some() {}
 }
 
 struct State<'a> {
-    tokens: std::iter::Peekable<std::slice::Iter<'a, Token>>,
+    tokens: &'a [(Token, Span)],
+    pos: usize,
+    // Inside absolute-value bars, where `|` closes the bars instead of being
+    // a bitwise or.
+    bars: bool,
+    depth: usize,
+    max_depth: usize,
+    diagnostics: Vec<Diagnostic>,
 }
 
-impl State<'_> {
-    fn peek(&mut self) -> Token {
-        **self.tokens.peek().unwrap()
+impl<'a> State<'a> {
+    fn new(tokens: &'a [(Token, Span)], max_depth: usize) -> Self {
+        State {
+            tokens,
+            pos: 0,
+            bars: false,
+            depth: 0,
+            max_depth,
+            diagnostics: Vec::new(),
+        }
+    }
+
+    // Reading past the end behaves as if the input ended with any number of
+    // `Eof` tokens.
+    fn peek(&self) -> &'a Token {
+        self.ahead(0)
+    }
+
+    // The token `offset` places ahead, `Eof` past the end.
+    fn ahead(&self, offset: usize) -> &'a Token {
+        self.tokens
+            .get(self.pos + offset)
+            .map_or(&Token::Eof, |(token, _)| token)
+    }
+
+    // The function name and parameter of a definition starting here with
+    // `f(x) =`.
+    fn definition(&self) -> Option<(&'a str, &'a str, Span)> {
+        let (Token::Ident(name), Token::Ident(param)) = (self.peek(), self.ahead(2)) else {
+            return None;
+        };
+        let delimited = *self.ahead(1) == Token::LeftParen
+            && *self.ahead(3) == Token::RightParen
+            && *self.ahead(4) == Token::Equals;
+        delimited.then(|| (name.as_str(), param.as_str(), self.span()))
+    }
+
+    fn peek_next(&self) -> Option<&'a Token> {
+        self.tokens.get(self.pos + 1).map(|(token, _)| token)
+    }
+
+    fn span(&self) -> Span {
+        match self.tokens.get(self.pos).or(self.tokens.last()) {
+            Some(&(_, span)) => span,
+            None => Span::new(0, 0),
+        }
+    }
+
+    // Whether the current token starts right where the previous one ended.
+    fn flush(&self) -> bool {
+        let Some(prev) = self
+            .pos
+            .checked_sub(1)
+            .and_then(|prev| self.tokens.get(prev))
+        else {
+            return false;
+        };
+        prev.1.end == self.span().start
+    }
+
+    // An operand followed by `(`, `√`, an identifier, or a number written flush
+    // against it (a constant, as in `2pi`) is an implicit multiplication.
+    // Two spaced-out numbers like `2 3` are still a missing operator.
+    fn implicit_mul(&self) -> bool {
+        match self.peek() {
+            Token::LeftParen | Token::Ident(_) | Token::Root => true,
+            Token::Num(_) => self.flush(),
+            _ => false,
+        }
+    }
+
+    // `%` written flush against its operand and not followed by another
+    // operand is a percent sign, so `50%` is 0.5 and `200 * 5% + 1` is 11.
+    // Anything else is modulo: `10 % 3`, `10%3`, `10 % -3`. A sign after the
+    // `%` is read as a binary operator, so `50%-3` is `0.5 - 3`.
+    fn percent_sign(&self) -> bool {
+        let next = self.peek_next().unwrap_or(&Token::Eof);
+        self.flush() && !next.starts_operand() && *next != Token::Root
     }
 
     fn eat(&mut self) {
-        self.tokens.next().unwrap();
+        self.pos += 1;
+    }
+
+    fn with_bars<T>(&mut self, bars: bool, f: impl FnOnce(&mut Self) -> T) -> T {
+        let outer = std::mem::replace(&mut self.bars, bars);
+        let result = f(self);
+        self.bars = outer;
+        result
+    }
+
+    // Runs one level deeper in the recursive descent, failing instead once
+    // `max_depth` levels are open.
+    fn nested<T>(&mut self, f: impl FnOnce(&mut Self) -> Result<T>) -> Result<T> {
+        if self.depth >= self.max_depth {
+            return Err(Error::ExpressionTooDeep(self.span()));
+        }
+        self.depth += 1;
+        let result = f(self);
+        self.depth -= 1;
+        result
     }
 }
 
-fn prat(tokens: &[Token]) -> Result<Expr> {
-    let mut state = State {
-        tokens: tokens.iter().peekable(),
-    };
-    parse_expr(&mut state, &Token::Eof)
+fn prat(tokens: &[(Token, Span)], max_depth: usize) -> Result<Expr> {
+    prat_diagnosed(tokens, max_depth).0
 }
 
-fn parse_expr(state: &mut State<'_>, end_token: &Token) -> Result<Expr> {
+fn prat_diagnosed(tokens: &[(Token, Span)], max_depth: usize) -> (Result<Expr>, Vec<Diagnostic>) {
+    if let [(Token::Eof, _)] = tokens {
+        return (Err(Error::EmptyExpression()), Vec::new());
+    }
+    if let Err(err) = check_parens(tokens, max_depth) {
+        return (Err(err), Vec::new());
+    }
+    let mut state = State::new(tokens, max_depth);
+    let expr = parse_expr(&mut state, &[Token::Eof]);
+    (expr, state.diagnostics)
+}
+
+// Parentheses are matched up front so that an unbalanced one is reported as
+// such rather than as whatever the parser trips over first. Nesting too deep
+// to parse is reported here as well, even if the parentheses never close.
+fn check_parens(tokens: &[(Token, Span)], max_depth: usize) -> Result<()> {
+    let mut open = Vec::new();
+    for (token, span) in tokens {
+        match token {
+            Token::LeftParen if open.len() + 1 >= max_depth => {
+                return Err(Error::ExpressionTooDeep(*span));
+            }
+            Token::LeftParen => open.push(*span),
+            Token::RightParen if open.pop().is_none() => {
+                return Err(Error::UnexpectedRightParen(*span));
+            }
+            _ => {}
+        }
+    }
+    match open.pop() {
+        Some(span) => Err(Error::UnclosedParen(span)),
+        None => Ok(()),
+    }
+}
+
+fn parse_expr(state: &mut State<'_>, end_tokens: &[Token]) -> Result<Expr> {
+    state.nested(|state| parse_expr_body(state, end_tokens))
+}
+
+fn parse_expr_body(state: &mut State<'_>, end_tokens: &[Token]) -> Result<Expr> {
+    if let Some((name, param, span)) = state.definition() {
+        state.pos += 5;
+        let body = parse_expr(state, end_tokens)?;
+        return define(name.to_owned(), param.to_owned(), body, span);
+    }
+    if let (Token::Ident(name), Some(Token::Equals)) = (state.peek(), state.peek_next()) {
+        state.eat();
+        state.eat();
+        let value = parse_expr(state, end_tokens)?;
+        return Ok(Expr::Assign {
+            name: name.clone(),
+            value: Box::new(value),
+        });
+    }
     let next = state.peek();
     let left = parse_unary(state, next)?;
-    let op = state.peek();
-    if &op == end_token {
-        state.eat(); // ???
-        return Ok(left);
+    let expr = parse_binary(state, left, 0)?;
+    if let Token::Question = state.peek() {
+        state.eat();
+        let then = parse_expr(state, &[Token::Colon])?;
+        state.eat();
+        let otherwise = parse_expr(state, end_tokens)?;
+        return Ok(Expr::Cond {
+            test: Box::new(expr),
+            then: Box::new(then),
+            otherwise: Box::new(otherwise),
+        });
     }
-    if !op.is_binary_op() {
-        return Err(Error::InvalidBinOp());
+    let next = state.peek();
+    if next.starts_operand() {
+        return Err(Error::MissingOperator(state.span()));
     }
-    parse_binary(state, left, end_token)
+    if !end_tokens.contains(next) {
+        return Err(Error::InvalidBinOp(state.span()));
+    }
+    Ok(expr)
 }
 
-fn parse_unary(state: &mut State<'_>, left: Token) -> Result<Expr> {
+// Signs chain, so `--5` is `5` and `-+-5` is `-(+(-5))`. A sign right after a
+// binary operator starts its operand: `2--3` is `2 - (-3)` and `2^-3` is
+// `2 ^ (-3)`. A sign binds looser than `^`, so `-x^2` is `-(x^2)`. `not`
+// takes in everything up to the next `and` or `or`, so `not a == b` is
+// `not (a == b)`.
+fn parse_unary(state: &mut State<'_>, left: &Token) -> Result<Expr> {
     if left.is_unary_op() {
+        let span = state.span();
         state.eat();
         let next = state.peek();
-        return Ok(apply_unary(left, parse_unary(state, next)?));
+        let expr = state.nested(|state| {
+            let expr = parse_unary(state, next)?;
+            match left {
+                Token::Not => parse_binary(state, expr, NOT_PRECEDENCE),
+                Token::Plus | Token::Minus => parse_binary(state, expr, SIGN_PRECEDENCE),
+                _ => Ok(expr),
+            }
+        })?;
+        return apply_unary(left, expr, span);
     }
+    let expr = parse_primary(state, left)?;
+    Ok(parse_postfix(state, expr))
+}
+
+fn parse_primary(state: &mut State<'_>, left: &Token) -> Result<Expr> {
     if let Token::LeftParen = left {
         state.eat();
-        return parse_expr(state, &Token::RightParen);
+        let expr = state.with_bars(false, |state| parse_expr(state, &[Token::RightParen]))?;
+        state.eat();
+        return Ok(expr);
+    }
+    // A `|` where an operand is expected opens absolute-value bars, and the
+    // next `|` after an operand closes them, so `||1| - 2|` nests. A bitwise
+    // or inside bars needs parentheses: `|(a | b)|`.
+    if let Token::Pipe = left {
+        let open = state.span();
+        state.eat();
+        let expr = state.with_bars(true, |state| {
+            parse_expr(
+                state,
+                &[
+                    Token::Pipe,
+                    Token::RightParen,
+                    Token::Comma,
+                    Token::Semicolon,
+                    Token::Colon,
+                    Token::Eof,
+                ],
+            )
+        })?;
+        if state.peek() != &Token::Pipe {
+            return Err(Error::UnclosedBar(open));
+        }
+        state.eat();
+        return Ok(Expr::Call {
+            name: "abs".to_owned(),
+            args: vec![expr],
+        });
     }
     if let Token::Num(value) = left {
         state.eat();
-        return Ok(Expr::Num(value));
+        #[cfg(feature = "units")]
+        if let Some(unit) = parse_unit(state)? {
+            return Ok(Expr::Mul {
+                lhs: Box::new(Expr::Num(*value)),
+                rhs: Box::new(unit),
+            });
+        }
+        return Ok(Expr::Num(*value));
+    }
+    if let Token::Ident(name) = left {
+        let span = state.span();
+        state.eat();
+        if state.peek() == &Token::LeftBracket {
+            return parse_index(state, name, span);
+        }
+        if state.peek() != &Token::LeftParen {
+            return Ok(Expr::Var(name.clone()));
+        }
+        state.eat();
+        return Ok(Expr::Call {
+            name: name.clone(),
+            args: state.with_bars(false, parse_args)?,
+        });
+    }
+    if let Token::Eof = left {
+        return Err(Error::UnexpectedEof(state.span()));
     }
-    Err(Error::InvalidUnaryOp())
+    Err(Error::InvalidUnaryOp(state.span()))
 }
 
-fn parse_binary(state: &mut State<'_>, left: Expr, end_token: &Token) -> Result<Expr> {
-    let op = state.peek();
+// `ans[n]` reads the `n`th result shown in the REPL and becomes a call to
+// `ans`. No other name can be indexed.
+fn parse_index(state: &mut State<'_>, name: &str, span: Span) -> Result<Expr> {
+    if name != "ans" {
+        return Err(Error::InvalidIdent(span));
+    }
+    let open = state.span();
     state.eat();
-    let next = state.peek();
-    let right = parse_unary(state, next)?;
-    let next = state.peek();
-    if &next == end_token {
-        state.eat(); // ???
-        return Ok(apply_binary(op, left, right));
+    let index = state.with_bars(false, |state| {
+        parse_expr(state, &[Token::RightBracket, Token::Eof])
+    })?;
+    if state.peek() != &Token::RightBracket {
+        return Err(Error::UnclosedParen(open));
+    }
+    state.eat();
+    Ok(Expr::Call {
+        name: name.to_owned(),
+        args: vec![index],
+    })
+}
+
+// A unit written flush against a number, with any power it is raised to,
+// binds to the number ahead of every other operator, so `10m / 2s` is
+// `(10 * m) / (2 * s)` and `3m^2` is `3 * m^2`. A name followed by `(` is a
+// call as usual.
+#[cfg(feature = "units")]
+fn parse_unit(state: &mut State<'_>) -> Result<Option<Expr>> {
+    let Token::Ident(name) = state.peek() else {
+        return Ok(None);
     };
-    if !next.is_binary_op() {
-        return Err(Error::InvalidBinOp());
-    }
-    if op.precedence() < next.precedence() {
-        return Ok(apply_binary(
-            op,
-            left,
-            parse_binary(state, right, end_token)?,
-        ));
+    if !state.flush()
+        || state.peek_next() == Some(&Token::LeftParen)
+        || crate::units::Quantity::unit(name).is_none()
+    {
+        return Ok(None);
+    }
+    let unit = Expr::Var(name.clone());
+    state.eat();
+    let precedence = Token::Caret.precedence().expect("`^` is a binary operator");
+    parse_binary(state, unit, precedence).map(Some)
+}
+
+fn parse_args(state: &mut State<'_>) -> Result<Vec<Expr>> {
+    let mut args = Vec::new();
+    if let Token::RightParen = state.peek() {
+        state.eat();
+        return Ok(args);
+    }
+    loop {
+        args.push(parse_expr(state, &[Token::Comma, Token::RightParen])?);
+        let end = state.peek();
+        state.eat();
+        if let Token::RightParen = end {
+            return Ok(args);
+        }
     }
-    parse_binary(state, apply_binary(op, left, right), end_token)
 }
 
-fn apply_unary(op: Token, expr: Expr) -> Expr {
-    match op {
+fn parse_postfix(state: &mut State<'_>, mut expr: Expr) -> Expr {
+    loop {
+        expr = match state.peek() {
+            Token::Bang => Expr::Factorial(Box::new(expr)),
+            Token::Percent if state.percent_sign() => Expr::Div {
+                lhs: Box::new(expr),
+                rhs: Box::new(Expr::Num(100.0)),
+            },
+            _ => return expr,
+        };
+        state.eat();
+    }
+}
+
+fn parse_binary(state: &mut State<'_>, mut left: Expr, min_precedence: usize) -> Result<Expr> {
+    loop {
+        let implicit = state.implicit_mul();
+        let op = if implicit { &Token::Star } else { state.peek() };
+        if state.bars && *op == Token::Pipe {
+            return Ok(left);
+        }
+        let Some((precedence, assoc)) = op.binary_op() else {
+            return Ok(left);
+        };
+        if precedence < min_precedence {
+            return Ok(left);
+        }
+        let span = state.span();
+        if implicit {
+            state.diagnostics.push(Diagnostic::ImplicitMul(span));
+        } else {
+            state.eat();
+        }
+        let next = state.peek();
+        let right = parse_unary(state, next)?;
+        let right = match assoc {
+            Assoc::Left => parse_binary(state, right, precedence + 1)?,
+            Assoc::Right => state.nested(|state| parse_binary(state, right, precedence))?,
+        };
+        left = apply_binary(op, left, right, span)?;
+    }
+}
+
+fn apply_unary(op: &Token, expr: Expr, span: Span) -> Result<Expr> {
+    Ok(match op {
         Token::Plus => Expr::Plus(Box::new(expr)),
         Token::Minus => Expr::Minus(Box::new(expr)),
-        _ => panic!("Illegal unary apply"),
+        Token::Root => Expr::Call {
+            name: "sqrt".to_owned(),
+            args: vec![expr],
+        },
+        Token::Not => Expr::Eq {
+            lhs: Box::new(expr),
+            rhs: Box::new(Expr::Num(0.0)),
+        },
+        _ => return Err(Error::InvalidUnaryOp(span)),
+    })
+}
+
+// Built-in functions and `ans` cannot be redefined.
+fn define(name: String, param: String, body: Expr, span: Span) -> Result<Expr> {
+    if name == "ans" || FUNCTIONS.contains(&name.as_str()) {
+        return Err(Error::InvalidIdent(span));
     }
+    Ok(Expr::Define {
+        name,
+        param,
+        body: Box::new(body),
+    })
 }
 
-fn apply_binary(op: Token, lhs: Expr, rhs: Expr) -> Expr {
-    match op {
+// `expr != 0`, which turns any truth value into 0 or 1.
+fn nonzero(expr: Expr) -> Expr {
+    Expr::Ne {
+        lhs: Box::new(expr),
+        rhs: Box::new(Expr::Num(0.0)),
+    }
+}
+
+fn apply_binary(op: &Token, lhs: Expr, rhs: Expr, span: Span) -> Result<Expr> {
+    Ok(match op {
         Token::Plus => Expr::Add {
             lhs: Box::new(lhs),
             rhs: Box::new(rhs),
@@ -213,6 +1455,835 @@ fn apply_binary(op: Token, lhs: Expr, rhs: Expr) -> Expr {
             lhs: Box::new(lhs),
             rhs: Box::new(rhs),
         },
-        _ => panic!("Illegal binary apply"),
+        Token::SlashSlash => Expr::FloorDiv {
+            lhs: Box::new(lhs),
+            rhs: Box::new(rhs),
+        },
+        Token::Percent => Expr::Mod {
+            lhs: Box::new(lhs),
+            rhs: Box::new(rhs),
+        },
+        Token::Caret => Expr::Pow {
+            base: Box::new(lhs),
+            exp: Box::new(rhs),
+        },
+        Token::EqualEqual => Expr::Eq {
+            lhs: Box::new(lhs),
+            rhs: Box::new(rhs),
+        },
+        Token::BangEqual => Expr::Ne {
+            lhs: Box::new(lhs),
+            rhs: Box::new(rhs),
+        },
+        Token::Less => Expr::Lt {
+            lhs: Box::new(lhs),
+            rhs: Box::new(rhs),
+        },
+        Token::LessEqual => Expr::Le {
+            lhs: Box::new(lhs),
+            rhs: Box::new(rhs),
+        },
+        Token::Greater => Expr::Gt {
+            lhs: Box::new(lhs),
+            rhs: Box::new(rhs),
+        },
+        Token::GreaterEqual => Expr::Ge {
+            lhs: Box::new(lhs),
+            rhs: Box::new(rhs),
+        },
+        Token::Amp => Expr::BitAnd {
+            lhs: Box::new(lhs),
+            rhs: Box::new(rhs),
+        },
+        Token::Pipe => Expr::BitOr {
+            lhs: Box::new(lhs),
+            rhs: Box::new(rhs),
+        },
+        Token::CaretCaret => Expr::BitXor {
+            lhs: Box::new(lhs),
+            rhs: Box::new(rhs),
+        },
+        Token::LessLess => Expr::Shl {
+            lhs: Box::new(lhs),
+            rhs: Box::new(rhs),
+        },
+        Token::GreaterGreater => Expr::Shr {
+            lhs: Box::new(lhs),
+            rhs: Box::new(rhs),
+        },
+        // Any nonzero operand counts as true and the result is always 0 or 1,
+        // so `0 or 2` is 1. The right operand sits in the branch that is only
+        // evaluated when it decides the result: `0 and 1 / 0` is 0 rather
+        // than an error.
+        Token::And => Expr::Cond {
+            test: Box::new(lhs),
+            then: Box::new(nonzero(rhs)),
+            otherwise: Box::new(Expr::Num(0.0)),
+        },
+        Token::Or => Expr::Cond {
+            test: Box::new(lhs),
+            then: Box::new(Expr::Num(1.0)),
+            otherwise: Box::new(nonzero(rhs)),
+        },
+        _ => return Err(Error::InvalidBinOp(span)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Pieces of the grammar, and of what it rejects, that random inputs are
+    // glued together from, so that most of them get past the lexer.
+    const FRAGMENTS: &[&str] = &[
+        "0", "1", "2.5", "1e3", "1e", "0x", "0xff", "0b2", "_", ".", "(", ")", "[", "]", "+", "-",
+        "*", "/", "//", "%", "^", "^^", "!", "!=", "=", "==", "<", "<<", "<=", ">", ">>", "?", ":",
+        ",", ";", "|", "&", "√", "−", "×", "÷", "x", "i", "ans", "sqrt", "max", "f", "pi", "inf",
+        "nan", "and", "or", "not", " ", "#", "é", "\n",
+    ];
+
+    // A xorshift generator, so that every run tries the same inputs.
+    fn random_inputs(count: usize) -> impl Iterator<Item = String> {
+        let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+        let mut next = move |bound: usize| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            (state % bound as u64) as usize
+        };
+        (0..count).map(move |_| {
+            let len = next(16);
+            (0..len).map(|_| FRAGMENTS[next(FRAGMENTS.len())]).collect()
+        })
+    }
+
+    // What the fuzz target in `fuzz/` checks, on inputs that `cargo test`
+    // can run without `cargo fuzz`.
+    #[test]
+    fn parse_never_panics() {
+        for input in random_inputs(50_000) {
+            let _ = parse_collect(&input);
+            let _ = parse_rpn(&input);
+            let _ = parse_chars(input.chars());
+            if let Ok(program) = parse_program(&input) {
+                for expr in program {
+                    let _ = expr.eval();
+                    let _ = expr.to_string();
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn constants_lex_as_numbers() {
+        use std::f64::consts::{E, PI};
+
+        assert_eq!(tokenize("pi").unwrap(), [Token::Num(PI), Token::Eof]);
+        assert_eq!(tokenize("e").unwrap(), [Token::Num(E), Token::Eof]);
+        assert_eq!(
+            parse("2 * pi").unwrap(),
+            Expr::Mul {
+                lhs: Box::new(Expr::Num(2.0)),
+                rhs: Box::new(Expr::Num(PI)),
+            }
+        );
+    }
+
+    #[test]
+    fn unknown_identifiers_are_variables() {
+        assert_eq!(parse("foo").unwrap(), Expr::Var("foo".to_owned()));
+        let err = parse("foo").unwrap().eval().unwrap_err();
+        assert!(matches!(err, Error::UndefinedVariable(name) if name == "foo"));
+    }
+
+    // These once panicked while the lexer looked for `inf`.
+    #[test]
+    fn prefixes_of_inf_are_identifiers() {
+        for name in ["i", "in", "inx"] {
+            assert_eq!(
+                tokenize(name).unwrap(),
+                [Token::Ident(name.to_owned()), Token::Eof]
+            );
+        }
+        assert_eq!(
+            tokenize("inf").unwrap(),
+            [Token::Num(f64::INFINITY), Token::Eof]
+        );
+    }
+
+    #[test]
+    fn blank_input_is_an_empty_expression() {
+        assert!(matches!(parse(""), Err(Error::EmptyExpression())));
+        assert!(matches!(parse("   "), Err(Error::EmptyExpression())));
+    }
+
+    #[test]
+    fn exponent_notation() {
+        assert_eq!(parse("1e3").unwrap(), Expr::Num(1000.0));
+        assert_eq!(parse("1.5e-2").unwrap(), Expr::Num(0.015));
+        assert_eq!(parse("2E4").unwrap(), Expr::Num(20000.0));
+        assert_eq!(parse("6.022e23").unwrap(), Expr::Num(6.022e23));
+    }
+
+    #[test]
+    fn exponent_without_digits_is_invalid() {
+        let err = parse("1e").unwrap_err();
+        assert!(matches!(err, Error::InvalidNumber(text, _) if text == "1e"));
+    }
+
+    #[test]
+    fn radix_literals() {
+        assert_eq!(parse("0xFF").unwrap(), Expr::Num(255.0));
+        assert_eq!(parse("0b1010").unwrap(), Expr::Num(10.0));
+        assert_eq!(parse("0o17").unwrap(), Expr::Num(15.0));
+    }
+
+    #[test]
+    fn bad_radix_literals_are_invalid() {
+        for input in ["0b102", "0x", "0o8"] {
+            let err = parse(input).unwrap_err();
+            assert!(matches!(err, Error::InvalidNumber(text, _) if text == input));
+        }
+    }
+
+    #[test]
+    fn tokens_carry_byte_spans() {
+        let spans: Vec<_> = tokenize_spanned("12 + x")
+            .unwrap()
+            .into_iter()
+            .map(|(_, span)| (span.start, span.end))
+            .collect();
+        assert_eq!(spans, [(0, 2), (3, 4), (5, 6), (6, 6)]);
+    }
+
+    #[test]
+    fn errors_carry_spans() {
+        let err = parse("1 + $").unwrap_err();
+        assert!(matches!(err, Error::UnexpectedChar('$', span) if span == Span::new(4, 1)));
+        // Offsets are in bytes, and `é` takes two.
+        let err = parse("é $").unwrap_err();
+        assert_eq!(err.span(), Some(Span::new(3, 1)));
+        let err = parse("12 + 0x").unwrap_err();
+        assert!(matches!(err, Error::InvalidNumber(_, span) if span == Span::new(5, 2)));
+        let err = parse("1 + * 2").unwrap_err();
+        assert!(matches!(err, Error::InvalidUnaryOp(span) if span == Span::new(4, 1)));
+    }
+
+    #[test]
+    fn adjacent_numbers_miss_an_operator() {
+        let err = parse("1 2").unwrap_err();
+        assert!(matches!(err, Error::MissingOperator(span) if span == Span::new(2, 1)));
+        let err = parse("(1) 2").unwrap_err();
+        assert!(matches!(err, Error::MissingOperator(span) if span == Span::new(4, 1)));
+    }
+
+    #[test]
+    fn a_parenthesis_after_an_operand_multiplies() {
+        let product = parse("3 * 4").unwrap();
+        assert_eq!(parse("3 (4)").unwrap(), product);
+        assert_eq!(parse("(3)(4)").unwrap(), product);
+    }
+
+    #[test]
+    fn numbers_with_several_points_are_malformed() {
+        for input in ["1.2.3", ".", "..5"] {
+            let err = parse(input).unwrap_err();
+            assert!(matches!(err, Error::MalformedNumber(text, _) if text == input));
+        }
+    }
+
+    #[test]
+    fn a_point_may_start_or_end_a_number() {
+        assert_eq!(parse(".5").unwrap(), Expr::Num(0.5));
+        assert_eq!(parse("5.").unwrap(), Expr::Num(5.0));
+    }
+
+    #[test]
+    fn tokenize_returns_every_token() {
+        use Token::*;
+
+        assert_eq!(
+            tokenize("1 + 2 * (3 - 4)").unwrap(),
+            [
+                Num(1.0),
+                Plus,
+                Num(2.0),
+                Star,
+                LeftParen,
+                Num(3.0),
+                Minus,
+                Num(4.0),
+                RightParen,
+                Eof,
+            ]
+        );
+    }
+
+    #[test]
+    fn implicit_multiplication_binds_like_a_star() {
+        assert_eq!(parse("2(3+4)").unwrap(), parse("2 * (3 + 4)").unwrap());
+        assert_eq!(
+            parse("(1+1)(2+2)").unwrap(),
+            parse("(1 + 1) * (2 + 2)").unwrap()
+        );
+        assert_eq!(parse("2pi").unwrap(), parse("2 * pi").unwrap());
+        assert_eq!(parse("2x^2").unwrap(), parse("2 * x ^ 2").unwrap());
+        assert_eq!(parse("1 + 2x").unwrap(), parse("1 + 2 * x").unwrap());
+    }
+
+    #[test]
+    fn adjacent_numbers_are_not_multiplied() {
+        assert!(matches!(parse("2 3"), Err(Error::MissingOperator(_))));
+    }
+
+    #[test]
+    fn parse_collect_reports_every_lexing_error() {
+        let (expr, errors) = parse_collect("1 + @ + 2 $");
+        assert!(expr.is_none());
+        assert!(matches!(
+            errors[..],
+            [Error::UnexpectedChar('@', _), Error::UnexpectedChar('$', _)]
+        ));
+    }
+
+    #[test]
+    fn parse_collect_parses_clean_input() {
+        let (expr, errors) = parse_collect("1 + 2");
+        assert_eq!(expr, Some(parse("1 + 2").unwrap()));
+        assert!(errors.is_empty());
+    }
+
+    #[test]
+    fn underscores_separate_digits() {
+        assert_eq!(parse("1_000").unwrap(), Expr::Num(1000.0));
+        assert_eq!(parse("1_000.5").unwrap(), Expr::Num(1000.5));
+        assert_eq!(parse("0xff_ff").unwrap(), Expr::Num(65535.0));
+    }
+
+    #[test]
+    fn underscores_must_be_between_digits() {
+        for input in ["1_", "_1", "1__0", "1_.5"] {
+            let err = parse(input).unwrap_err();
+            assert!(
+                matches!(err, Error::InvalidNumber(text, _) if text == input),
+                "{input}"
+            );
+        }
+    }
+
+    #[test]
+    fn comments_run_to_the_end_of_the_line() {
+        assert_eq!(
+            parse("2 + 2 # sanity check").unwrap(),
+            parse("2 + 2").unwrap()
+        );
+        assert_eq!(parse("1 +# x\n2").unwrap(), parse("1 + 2").unwrap());
+        assert!(matches!(parse("# comment"), Err(Error::EmptyExpression())));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn json_round_trips() {
+        for input in [
+            "1 + 2 * x",
+            "max(1, -2) ? y = 3! : f(x) = x ^ 2",
+            "|-3| % 2 >> 1",
+        ] {
+            let expr = parse(input).unwrap();
+            let json = serde_json::to_string(&expr).unwrap();
+            assert_eq!(
+                serde_json::from_str::<Expr>(&json).unwrap(),
+                expr,
+                "{input}"
+            );
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn json_has_a_field_per_operand() {
+        let json = serde_json::to_value(parse("1 + x").unwrap()).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({"Add": {"lhs": {"Num": 1.0}, "rhs": {"Var": "x"}}})
+        );
+    }
+
+    #[test]
+    fn unclosed_parentheses_point_at_the_opening_one() {
+        let err = parse("(1+2").unwrap_err();
+        assert!(matches!(err, Error::UnclosedParen(span) if span == Span::new(0, 1)));
+        let err = parse("((1)").unwrap_err();
+        assert!(matches!(err, Error::UnclosedParen(span) if span == Span::new(0, 1)));
+        let err = parse("1 + (2 * (3)").unwrap_err();
+        assert!(matches!(err, Error::UnclosedParen(span) if span == Span::new(4, 1)));
+    }
+
+    #[test]
+    fn stray_closing_parentheses_are_reported() {
+        let err = parse("1+2)").unwrap_err();
+        assert!(matches!(err, Error::UnexpectedRightParen(span) if span == Span::new(3, 1)));
+        let err = parse(")(").unwrap_err();
+        assert!(matches!(err, Error::UnexpectedRightParen(span) if span == Span::new(0, 1)));
+    }
+
+    #[test]
+    fn precedence_follows_the_table() {
+        let cases = [
+            ("1 + 2 * 3 - 4 / 5", "(- (+ 1 (* 2 3)) (/ 4 5))"),
+            ("10 / 5 // 2 % 3", "(% (// (/ 10 5) 2) 3)"),
+            ("1 | 2 ^^ 3 & 4", "(| 1 (^^ 2 (& 3 4)))"),
+            ("1 == 2 < 3", "(== 1 (< 2 3))"),
+            ("1 << 2 + 3", "(<< 1 (+ 2 3))"),
+            ("1 < 2 ? 3 : 4", "(? (< 1 2) 3 4)"),
+            ("x = y = 2", "(= x (= y 2))"),
+        ];
+        for (input, sexpr) in cases {
+            assert_eq!(parse(input).unwrap().to_sexpr(), sexpr, "{input}");
+        }
+    }
+
+    #[test]
+    fn precedence_exposes_the_table() {
+        assert_eq!(Token::Plus.precedence(), Some(9));
+        assert!(Token::Star.precedence() > Token::Plus.precedence());
+        assert!(Token::Caret.precedence() > Token::Star.precedence());
+        assert_eq!(Token::LeftParen.precedence(), None);
+        for (op, precedence, assoc) in BINARY_OPS {
+            assert_eq!(op.binary_op(), Some((*precedence, *assoc)));
+        }
+    }
+
+    #[test]
+    fn truncated_input_is_an_error() {
+        let inputs = [
+            "(", "sqrt(", "max(1,", "1 ?", "1 ? 2 :", "|", "ans[", "f(x) =", "0x", "1e+", "√",
+        ];
+        for input in inputs {
+            assert!(parse(input).is_err(), "{input}");
+            assert!(parse_rpn(input).is_err(), "{input}");
+        }
+    }
+
+    #[test]
+    fn deep_nesting_is_too_deep_rather_than_a_crash() {
+        let input = "(".repeat(100_000);
+        assert!(matches!(parse(&input), Err(Error::ExpressionTooDeep(_))));
+        let input = format!("{}1{}", "(".repeat(100_000), ")".repeat(100_000));
+        assert!(matches!(parse(&input), Err(Error::ExpressionTooDeep(_))));
+        let input = "-".repeat(100_000) + "1";
+        assert!(matches!(parse(&input), Err(Error::ExpressionTooDeep(_))));
+    }
+
+    #[test]
+    fn max_depth_is_configurable() {
+        let input = "((((1))))";
+        assert!(parse_with_max_depth(input, 5).is_ok());
+        assert!(matches!(
+            parse_with_max_depth(input, 3),
+            Err(Error::ExpressionTooDeep(_))
+        ));
+    }
+
+    #[test]
+    fn validate_accepts_what_parse_accepts() {
+        for input in ["1 + 2", "x = max(1, 2)", "f(x) = x ^ 2"] {
+            assert!(validate(input).is_ok(), "{input}");
+        }
+    }
+
+    #[test]
+    fn validate_reports_the_errors_parse_does() {
+        for input in ["1 +", "(1", "1 2", "1.2.3", "$", ""] {
+            let expected = parse(input).unwrap_err().to_string();
+            assert_eq!(
+                validate(input).unwrap_err().to_string(),
+                expected,
+                "{input}"
+            );
+            assert_eq!(
+                validate(input).unwrap_err().span(),
+                parse(input).unwrap_err().span()
+            );
+        }
+    }
+
+    #[test]
+    fn every_binary_operator_has_a_level() {
+        use Token::*;
+
+        let levels = [
+            (vec![Or], 1),
+            (vec![And], 2),
+            (vec![Pipe], 3),
+            (vec![CaretCaret], 4),
+            (vec![Amp], 5),
+            (vec![EqualEqual, BangEqual], 6),
+            (vec![Less, LessEqual, Greater, GreaterEqual], 7),
+            (vec![LessLess, GreaterGreater], 8),
+            (vec![Plus, Minus], 9),
+            (vec![Star, Slash, SlashSlash, Percent], 10),
+            (vec![Caret], 11),
+        ];
+        for (ops, level) in levels {
+            for op in ops {
+                assert_eq!(op.precedence(), Some(level), "{op:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn other_tokens_have_no_level() {
+        for token in [
+            Token::LeftParen,
+            Token::Num(1.0),
+            Token::Eof,
+            Token::Bang,
+            Token::Equals,
+        ] {
+            assert_eq!(token.precedence(), None, "{token:?}");
+        }
+    }
+
+    #[test]
+    fn tau_and_phi() {
+        use std::f64::consts::{PI, TAU};
+
+        assert_eq!(parse("tau").unwrap(), Expr::Num(TAU));
+        assert_eq!(parse("tau / 2").unwrap().eval().unwrap(), PI);
+        assert_eq!(parse("phi").unwrap(), Expr::Num((1.0 + 5f64.sqrt()) / 2.0));
+    }
+
+    #[test]
+    fn trailing_operators_expect_an_operand() {
+        for input in ["2 +", "3 *", "+", "1 <"] {
+            let err = parse(input).unwrap_err();
+            let end = Span::new(input.len(), 0);
+            assert!(
+                matches!(err, Error::UnexpectedEof(span) if span == end),
+                "{input}"
+            );
+        }
+        // The unclosed parenthesis is found first.
+        assert!(matches!(parse("(1 +"), Err(Error::UnclosedParen(_))));
+    }
+
+    #[test]
+    fn rpn_builds_the_same_tree() {
+        assert_eq!(
+            parse_rpn("3 4 + 5 *").unwrap(),
+            parse("(3 + 4) * 5").unwrap()
+        );
+        assert_eq!(parse_rpn("2 x neg ^").unwrap(), parse("2 ^ -x").unwrap());
+        assert_eq!(
+            parse_rpn("1 2 3 max/3").unwrap(),
+            parse("max(1, 2, 3)").unwrap()
+        );
+        assert_eq!(parse_rpn("x 1 =").unwrap(), parse("x = 1").unwrap());
+        for input in ["1 + 2 * 3", "f(x) = x ^ 2", "x < 1 ? -x : x!"] {
+            let expr = parse(input).unwrap();
+            assert_eq!(
+                parse_rpn(&expr.to_rpn().join(" ")).unwrap(),
+                expr,
+                "{input}"
+            );
+        }
+    }
+
+    #[test]
+    fn rpn_underflow_is_an_error() {
+        assert!(matches!(parse_rpn("1 +"), Err(Error::RpnUnderflow(_))));
+        assert!(matches!(parse_rpn("neg"), Err(Error::RpnUnderflow(_))));
+        assert!(matches!(
+            parse_rpn("1 2 max/3"),
+            Err(Error::RpnUnderflow(_))
+        ));
+    }
+
+    #[test]
+    fn rpn_extra_operands_are_an_error() {
+        assert!(matches!(parse_rpn("1 2"), Err(Error::RpnExtraOperands(1))));
+        assert!(matches!(
+            parse_rpn("1 2 3 +"),
+            Err(Error::RpnExtraOperands(1))
+        ));
+        assert!(matches!(parse_rpn(""), Err(Error::EmptyExpression())));
+        assert!(matches!(parse_rpn("1 ("), Err(Error::InvalidRpnToken(_))));
+    }
+
+    fn comma_config() -> EvalConfig {
+        EvalConfig {
+            decimal_separator: DecimalSeparator::Comma,
+            ..EvalConfig::default()
+        }
+    }
+
+    #[test]
+    fn comma_can_be_the_decimal_separator() {
+        let config = comma_config();
+        let expr = parse_with_config("3,14 + 1", &config).unwrap();
+        assert!((expr.eval().unwrap() - 4.14).abs() < 1e-12);
+        let expr = parse_with_config("max(1,5; 2)", &config).unwrap();
+        assert_eq!(expr.eval().unwrap(), 2.0);
+    }
+
+    #[test]
+    fn dot_is_the_default_decimal_separator() {
+        let config = EvalConfig::default();
+        assert_eq!(config.decimal_separator, DecimalSeparator::Dot);
+        let expr = parse_with_config("max(1,5)", &config).unwrap();
+        assert_eq!(expr.eval().unwrap(), 5.0);
+        assert!(parse_with_config("1;5", &config).is_err());
+    }
+
+    #[test]
+    fn implicit_multiplication_is_diagnosed() {
+        let (expr, diagnostics) = parse_with_diagnostics("2pi");
+        assert_eq!(expr.unwrap(), parse("2 * pi").unwrap());
+        assert_eq!(diagnostics, [Diagnostic::ImplicitMul(Span::new(1, 2))]);
+        let (_, diagnostics) = parse_with_diagnostics("2(3) + x y");
+        assert_eq!(diagnostics.len(), 2);
+    }
+
+    #[test]
+    fn explicit_input_has_no_diagnostics() {
+        let (expr, diagnostics) = parse_with_diagnostics("2 * pi");
+        assert!(expr.is_ok());
+        assert!(diagnostics.is_empty());
+        let (expr, diagnostics) = parse_with_diagnostics("2x +");
+        assert!(expr.is_err());
+        assert_eq!(diagnostics.len(), 1);
+    }
+
+    // Compared through `Debug`, since `nan` lexes to a NaN that is not equal to itself.
+    #[test]
+    fn lexer_matches_the_batch_tokenizer() {
+        let inputs = [
+            "1 + 2 * 3",
+            "max(1_000, 0x1f) != 2e-3",
+            "é",
+            "x = √4 ^^ 1",
+            "3 $ 4",
+        ];
+        for input in inputs
+            .into_iter()
+            .map(str::to_owned)
+            .chain(random_inputs(2_000))
+        {
+            let lazy: Vec<_> = Lexer::new(input.chars())
+                .map(|token| format!("{token:?}"))
+                .collect();
+            let batch: Vec<_> = lex_str(&input, DecimalSeparator::Dot)
+                .map(|token| format!("{token:?}"))
+                .collect();
+            assert_eq!(lazy, batch, "{input}");
+        }
+    }
+
+    #[test]
+    fn lexer_reads_only_what_it_needs() {
+        let chars = "12 + ".chars().chain(std::iter::repeat('x'));
+        let mut lexer = Lexer::new(chars);
+        assert_eq!(
+            lexer.next().unwrap().unwrap(),
+            (Token::Num(12.0), Span::new(0, 2))
+        );
+        assert_eq!(
+            lexer.next().unwrap().unwrap(),
+            (Token::Plus, Span::new(3, 1))
+        );
+    }
+
+    #[test]
+    fn parse_chars_matches_parse() {
+        assert_eq!(
+            parse_chars("1 + 2 * 3".chars()).unwrap(),
+            parse("1 + 2 * 3").unwrap()
+        );
+        assert!(matches!(
+            parse_chars("(1".chars()),
+            Err(Error::UnclosedParen(_))
+        ));
+    }
+
+    // Numbers are sliced out of the input, so check the slice bounds after
+    // multi-byte characters and at the very end.
+    #[test]
+    fn numbers_after_multibyte_characters() {
+        assert_eq!(
+            tokenize_spanned("√2.25").unwrap(),
+            [
+                (Token::Root, Span::new(0, 3)),
+                (Token::Num(2.25), Span::new(3, 4)),
+                (Token::Eof, Span::new(7, 0)),
+            ]
+        );
+        assert_eq!(
+            tokenize("3×1_000÷0x10").unwrap(),
+            [
+                Token::Num(3.0),
+                Token::Star,
+                Token::Num(1000.0),
+                Token::Slash,
+                Token::Num(16.0),
+                Token::Eof,
+            ]
+        );
+        assert_eq!(
+            tokenize("6.02e23").unwrap(),
+            [Token::Num(6.02e23), Token::Eof]
+        );
+    }
+
+    #[test]
+    fn only_ans_can_be_indexed() {
+        assert_eq!(
+            parse("ans[2]").unwrap(),
+            Expr::Call {
+                name: "ans".to_owned(),
+                args: vec![Expr::Num(2.0)],
+            }
+        );
+        assert!(matches!(parse("x[1]"), Err(Error::InvalidIdent(_))));
+        assert!(matches!(parse("ans[1"), Err(Error::UnclosedParen(_))));
+    }
+
+    #[test]
+    fn unicode_operators_lex_like_ascii() {
+        assert_eq!(tokenize("6 × 7").unwrap(), tokenize("6 * 7").unwrap());
+        assert_eq!(tokenize("10 ÷ 2").unwrap(), tokenize("10 / 2").unwrap());
+        assert_eq!(tokenize("5 − 3").unwrap(), tokenize("5 - 3").unwrap());
+        assert_eq!(
+            tokenize("√9").unwrap(),
+            [Token::Root, Token::Num(9.0), Token::Eof]
+        );
+        assert_eq!(
+            parse("6 × 7 − 10 ÷ 2").unwrap(),
+            parse("6 * 7 - 10 / 2").unwrap()
+        );
+        assert_eq!(
+            parse("−√(3 × 3) * 2").unwrap(),
+            parse("-sqrt(3 * 3) * 2").unwrap()
+        );
+    }
+
+    #[test]
+    fn unicode_operators_have_byte_spans() {
+        let spans: Vec<_> = tokenize_spanned("1×2")
+            .unwrap()
+            .into_iter()
+            .map(|(_, span)| (span.start, span.end))
+            .collect();
+        assert_eq!(spans, [(0, 1), (1, 3), (3, 4), (4, 4)]);
+    }
+
+    #[test]
+    fn parse_tokens_reads_hand_built_tokens() {
+        let tokens = [
+            Token::Num(1.0),
+            Token::Plus,
+            Token::Num(2.0),
+            Token::Star,
+            Token::Num(3.0),
+        ];
+        assert_eq!(parse_tokens(&tokens).unwrap(), parse("1 + 2 * 3").unwrap());
+        let mut with_eof = tokens.to_vec();
+        with_eof.push(Token::Eof);
+        assert_eq!(
+            parse_tokens(&with_eof).unwrap(),
+            parse("1 + 2 * 3").unwrap()
+        );
+        let tokens = [
+            Token::Ident("sqrt".to_owned()),
+            Token::LeftParen,
+            Token::Num(16.0),
+            Token::RightParen,
+        ];
+        assert_eq!(parse_tokens(&tokens).unwrap().eval().unwrap(), 4.0);
+        let tokens = [Token::Num(50.0), Token::Percent];
+        assert_eq!(parse_tokens(&tokens).unwrap().eval().unwrap(), 0.5);
+    }
+
+    #[test]
+    fn parse_tokens_reports_token_positions() {
+        let tokens = [Token::Num(1.0), Token::Eof, Token::Num(2.0)];
+        assert!(matches!(
+            parse_tokens(&tokens),
+            Err(Error::InvalidBinOp(span)) if span == Span::new(1, 1)
+        ));
+        let tokens = [Token::Num(1.0), Token::Plus];
+        assert!(matches!(
+            parse_tokens(&tokens),
+            Err(Error::UnexpectedEof(span)) if span == Span::new(2, 0)
+        ));
+        assert!(matches!(parse_tokens(&[]), Err(Error::EmptyExpression())));
+    }
+
+    #[test]
+    fn nan_lexes_as_a_number() {
+        let tokens = tokenize("nan").unwrap();
+        assert!(matches!(tokens[..], [Token::Num(num), Token::Eof] if num.is_nan()));
+        assert!(matches!(parse("nan").unwrap(), Expr::Num(num) if num.is_nan()));
+        assert!(parse("nan + 1").unwrap().eval().unwrap().is_nan());
+        assert_eq!(
+            tokenize("nana").unwrap()[0],
+            Token::Ident("nana".to_owned())
+        );
+    }
+
+    #[test]
+    fn nan_compares_unequal_to_itself() {
+        assert_eq!(parse("nan == nan").unwrap().eval().unwrap(), 0.0);
+        assert_eq!(parse("nan != nan").unwrap().eval().unwrap(), 1.0);
+        assert_eq!(parse("nan < 1 or nan >= 1").unwrap().eval().unwrap(), 0.0);
+    }
+
+    #[test]
+    fn nan_prints_as_it_is_written() {
+        let expr = parse("nan * 2").unwrap();
+        assert_eq!(expr.to_string(), "nan * 2");
+        assert!(parse(&expr.to_string()).unwrap().structurally_eq(&expr));
+    }
+
+    #[test]
+    fn clones_evaluate_like_the_original() {
+        for input in ["(1 + 2) * 4", "max(3, 2 ^ 5) - 7!", "1 < 2 ? sqrt(16) : -1"] {
+            let expr = parse(input).unwrap();
+            let clone = expr.clone();
+            assert_eq!(clone, expr, "{input}");
+            assert_eq!(clone.eval().unwrap(), expr.eval().unwrap(), "{input}");
+        }
+    }
+
+    #[test]
+    fn clones_are_independent() {
+        let expr = parse("x + 1").unwrap();
+        let mut clone = expr.clone();
+        if let Expr::Add { lhs, .. } = &mut clone {
+            **lhs = Expr::Num(41.0);
+        }
+        assert_eq!(clone.eval().unwrap(), 42.0);
+        assert_eq!(expr, parse("x + 1").unwrap());
+        drop(expr);
+        assert_eq!(clone.to_string(), "41 + 1");
+    }
+
+    #[test]
+    fn deep_trees_print_compare_and_clone() {
+        let input = vec!["1"; 200_000].join("+");
+        let expr = parse(&input).unwrap();
+        let clone = expr.clone();
+        assert!(clone == expr);
+        assert!(clone.structurally_eq(&expr));
+        assert_eq!(expr.to_string().len(), input.len() + 2 * 199_999);
+        let sexpr = expr.to_sexpr();
+        assert!(sexpr.starts_with("(+ (+ (+"));
+        assert!(sexpr.contains("(+ 1 1) 1) 1)"));
+        let mut deep = Expr::Num(1.0);
+        for _ in 0..200_000 {
+            deep = Expr::Minus(Box::new(deep));
+        }
+        assert_eq!(deep.to_string().len(), 200_001);
+        assert_eq!(deep.clone(), deep);
     }
 }
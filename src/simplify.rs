@@ -0,0 +1,149 @@
+use std::mem;
+
+use crate::parser::Expr;
+
+impl Expr {
+    // Folds constant subtrees into numbers and drops identity operations such
+    // as `x * 1` or `x + 0`. Subtrees whose evaluation fails (`1 / 0`) are
+    // left alone so that evaluating the result still reports the error.
+    pub fn simplify(mut self) -> Expr {
+        for child in self.children_mut() {
+            *child = mem::replace(child, Expr::Num(0.0)).simplify();
+        }
+        if self.foldable() {
+            if let Ok(value) = self.eval() {
+                return Expr::Num(value);
+            }
+        }
+        identity(self)
+    }
+
+    // Whether the node can be replaced by its value: every operand is already
+    // a number and the result does not depend on the environment. Trig calls
+    // are kept because their meaning depends on the angle mode, and `ans[n]`
+    // because it reads an earlier result.
+    fn foldable(&self) -> bool {
+        match self {
+            Expr::Num(_) | Expr::Var(_) | Expr::Assign { .. } | Expr::Define { .. } => false,
+            Expr::Call { name, .. } if matches!(name.as_str(), "sin" | "cos" | "tan" | "ans") => {
+                false
+            }
+            _ => self
+                .children()
+                .iter()
+                .all(|child| matches!(child, Expr::Num(_))),
+        }
+    }
+}
+
+fn is_num(expr: &Expr, value: f64) -> bool {
+    matches!(expr, Expr::Num(num) if *num == value)
+}
+
+fn same_var(lhs: &Expr, rhs: &Expr) -> bool {
+    matches!((lhs, rhs), (Expr::Var(lhs), Expr::Var(rhs)) if lhs == rhs)
+}
+
+// `x + x` becomes `2 * x`. `x * 0` and `x ^ 0` only drop `x` when it is a
+// plain variable, so that assignments inside it still happen.
+fn identity(mut expr: Expr) -> Expr {
+    match &mut expr {
+        Expr::Add { lhs, rhs } if is_num(rhs, 0.0) => take(lhs),
+        Expr::Add { lhs, rhs } if is_num(lhs, 0.0) => take(rhs),
+        Expr::Add { lhs, rhs } if same_var(lhs, rhs) => mul(Expr::Num(2.0), take(lhs)),
+        Expr::Sub { lhs, rhs } if is_num(rhs, 0.0) => take(lhs),
+        Expr::Mul { lhs, rhs } if is_num(rhs, 1.0) => take(lhs),
+        Expr::Mul { lhs, rhs } if is_num(lhs, 1.0) => take(rhs),
+        Expr::Mul { lhs, rhs }
+            if is_num(rhs, 0.0) && matches!(**lhs, Expr::Var(_))
+                || is_num(lhs, 0.0) && matches!(**rhs, Expr::Var(_)) =>
+        {
+            Expr::Num(0.0)
+        }
+        Expr::Div { lhs, rhs } if is_num(rhs, 1.0) => take(lhs),
+        Expr::Pow { base, exp } if is_num(exp, 1.0) => take(base),
+        Expr::Pow { base, exp } if is_num(exp, 0.0) && matches!(**base, Expr::Var(_)) => {
+            Expr::Num(1.0)
+        }
+        Expr::Cond {
+            test,
+            then,
+            otherwise,
+        } if matches!(**test, Expr::Num(_)) => {
+            if is_num(test, 0.0) {
+                take(otherwise)
+            } else {
+                take(then)
+            }
+        }
+        _ => expr,
+    }
+}
+
+// `Expr` cannot be moved out of because it implements `Drop`, so a kept
+// operand is swapped out for a placeholder instead.
+fn take(expr: &mut Expr) -> Expr {
+    mem::replace(expr, Expr::Num(0.0))
+}
+
+fn mul(lhs: Expr, rhs: Expr) -> Expr {
+    Expr::Mul {
+        lhs: Box::new(lhs),
+        rhs: Box::new(rhs),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        eval::Environment,
+        parser::{parse, Expr},
+    };
+
+    fn simplified(input: &str) -> Expr {
+        parse(input).unwrap().simplify()
+    }
+
+    #[test]
+    fn closed_expressions_fold_to_a_number() {
+        assert_eq!(simplified("2 + 3 * 4"), Expr::Num(14.0));
+        assert_eq!(simplified("sqrt(16) - 2 ^ 3"), Expr::Num(-4.0));
+    }
+
+    #[test]
+    fn identities_are_dropped() {
+        assert_eq!(simplified("x * 1"), parse("x").unwrap());
+        assert_eq!(simplified("0 + x"), parse("x").unwrap());
+        assert_eq!(simplified("x * (3 - 3)"), Expr::Num(0.0));
+        assert_eq!(simplified("x + x"), parse("2 * x").unwrap());
+        assert_eq!(simplified("(y + 2 * 3) ^ 1"), parse("y + 6").unwrap());
+    }
+
+    #[test]
+    fn failing_and_environment_dependent_subtrees_are_kept() {
+        assert_eq!(simplified("1 / 0"), parse("1 / 0").unwrap());
+        assert_eq!(simplified("sin(0)"), parse("sin(0)").unwrap());
+        assert_eq!(simplified("(x = 2) * 0"), parse("(x = 2) * 0").unwrap());
+    }
+
+    #[test]
+    fn simplifying_preserves_the_value() {
+        for input in [
+            "x * 1 + 0",
+            "(x + x) / 2 ^ 1",
+            "x ^ 0 + 3!",
+            "1 ? x : y",
+            "x - 0 * x",
+        ] {
+            let mut env = Environment::new();
+            env.set("x", 3.5);
+            env.set("y", -2.0);
+            let expected = parse(input).unwrap().eval_in(&mut env).unwrap();
+            assert_eq!(
+                simplified(input).eval_in(&mut env).unwrap(),
+                expected,
+                "{input}"
+            );
+        }
+    }
+}
@@ -0,0 +1,303 @@
+use std::fmt::{self, Display, Formatter};
+
+use crate::{
+    error::{Error, Result},
+    eval::{apply, arity, Environment},
+    parser::Expr,
+};
+
+// Exponents of the SI base units, in the order `BASE_UNITS` names them.
+type Dimension = [i8; 7];
+
+const BASE_UNITS: [&str; 7] = ["kg", "m", "s", "A", "K", "mol", "cd"];
+
+const DIMENSIONLESS: Dimension = [0; 7];
+const MASS: Dimension = [1, 0, 0, 0, 0, 0, 0];
+const LENGTH: Dimension = [0, 1, 0, 0, 0, 0, 0];
+const TIME: Dimension = [0, 0, 1, 0, 0, 0, 0];
+const CURRENT: Dimension = [0, 0, 0, 1, 0, 0, 0];
+const TEMPERATURE: Dimension = [0, 0, 0, 0, 1, 0, 0];
+const AMOUNT: Dimension = [0, 0, 0, 0, 0, 1, 0];
+const LUMINOSITY: Dimension = [0, 0, 0, 0, 0, 0, 1];
+const VOLUME: Dimension = [0, 3, 0, 0, 0, 0, 0];
+const FREQUENCY: Dimension = [0, 0, -1, 0, 0, 0, 0];
+const FORCE: Dimension = [1, 1, -2, 0, 0, 0, 0];
+const PRESSURE: Dimension = [1, -1, -2, 0, 0, 0, 0];
+const ENERGY: Dimension = [1, 2, -2, 0, 0, 0, 0];
+const POWER: Dimension = [1, 2, -3, 0, 0, 0, 0];
+
+// Every unit a quantity can be written in, with its size in SI base units.
+// Temperatures are absolute, so only kelvin is offered.
+const UNITS: &[(&str, f64, Dimension)] = &[
+    ("kg", 1.0, MASS),
+    ("g", 1e-3, MASS),
+    ("mg", 1e-6, MASS),
+    ("t", 1e3, MASS),
+    ("lb", 0.453_592_37, MASS),
+    ("m", 1.0, LENGTH),
+    ("km", 1e3, LENGTH),
+    ("cm", 1e-2, LENGTH),
+    ("mm", 1e-3, LENGTH),
+    ("um", 1e-6, LENGTH),
+    ("nm", 1e-9, LENGTH),
+    ("in", 0.0254, LENGTH),
+    ("ft", 0.3048, LENGTH),
+    ("mi", 1609.344, LENGTH),
+    ("s", 1.0, TIME),
+    ("ms", 1e-3, TIME),
+    ("min", 60.0, TIME),
+    ("h", 3600.0, TIME),
+    ("A", 1.0, CURRENT),
+    ("mA", 1e-3, CURRENT),
+    ("K", 1.0, TEMPERATURE),
+    ("mol", 1.0, AMOUNT),
+    ("cd", 1.0, LUMINOSITY),
+    ("L", 1e-3, VOLUME),
+    ("mL", 1e-6, VOLUME),
+    ("Hz", 1.0, FREQUENCY),
+    ("kHz", 1e3, FREQUENCY),
+    ("N", 1.0, FORCE),
+    ("kN", 1e3, FORCE),
+    ("Pa", 1.0, PRESSURE),
+    ("kPa", 1e3, PRESSURE),
+    ("J", 1.0, ENERGY),
+    ("kJ", 1e3, ENERGY),
+    ("W", 1.0, POWER),
+    ("kW", 1e3, POWER),
+];
+
+// The result of `eval_quantity`: a value in SI base units together with the
+// dimension it has, so `50cm` is 0.5 with the dimension of a length.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Quantity {
+    pub value: f64,
+    dimension: Dimension,
+}
+
+impl Quantity {
+    // One of the named unit, or `None` if there is no unit of that name.
+    pub fn unit(name: &str) -> Option<Quantity> {
+        UNITS
+            .iter()
+            .find(|&&(unit, ..)| unit == name)
+            .map(|&(_, value, dimension)| Quantity { value, dimension })
+    }
+
+    pub fn is_dimensionless(&self) -> bool {
+        self.dimension == DIMENSIONLESS
+    }
+
+    // The value expressed in the named unit, so `3.5m` in `cm` is 350. `None`
+    // if there is no such unit or it measures something else.
+    pub fn value_in(&self, unit: &str) -> Option<f64> {
+        let unit = Quantity::unit(unit)?;
+        (unit.dimension == self.dimension).then(|| self.value / unit.value)
+    }
+
+    // The SI base units the value is in, such as `m/s` or `1/s`, and empty
+    // for a plain number.
+    pub fn units(&self) -> String {
+        if self.is_dimensionless() {
+            return String::new();
+        }
+        let units = |sign: i8| -> Vec<String> {
+            BASE_UNITS
+                .iter()
+                .zip(self.dimension)
+                .filter(|&(_, exp)| exp.signum() == sign)
+                .map(|(unit, exp)| match exp.abs() {
+                    1 => unit.to_string(),
+                    exp => format!("{unit}^{exp}"),
+                })
+                .collect()
+        };
+        let (numerator, denominator) = (units(1), units(-1));
+        let numerator = if numerator.is_empty() {
+            "1".to_owned()
+        } else {
+            numerator.join("·")
+        };
+        match denominator.len() {
+            0 => numerator,
+            1 => format!("{numerator}/{}", denominator[0]),
+            _ => format!("{numerator}/({})", denominator.join("·")),
+        }
+    }
+}
+
+impl From<f64> for Quantity {
+    fn from(value: f64) -> Self {
+        Quantity {
+            value,
+            dimension: DIMENSIONLESS,
+        }
+    }
+}
+
+// Written in SI base units: `3.5 m`, `5 m/s`, `2 kg·m/s^2`.
+impl Display for Quantity {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.value)?;
+        if !self.is_dimensionless() {
+            write!(f, " {}", self.units())?;
+        }
+        Ok(())
+    }
+}
+
+impl Expr {
+    // Evaluates with units: a variable that is not defined but names a unit,
+    // such as `m` or `kg`, is one of that unit, so `3m + 50cm` is `3.5 m` and
+    // `10m / 2s` is `5 m/s`. Adding, comparing or taking the remainder of
+    // quantities of different dimensions, as in `3m + 2s`, fails with
+    // `Error::UnitMismatch`, as does applying anything but `abs`, `min`,
+    // `max` and `sqrt` to a quantity with a dimension. Variables hold only
+    // plain numbers.
+    pub fn eval_quantity(&self) -> Result<Quantity> {
+        self.eval_quantity_in(&mut Environment::new())
+    }
+
+    pub fn eval_quantity_in(&self, env: &mut Environment) -> Result<Quantity> {
+        self.walk(env, apply_quantity, |quantity| quantity.value != 0.0)
+    }
+}
+
+// The value is worked out by the `f64` evaluator on the values in SI base
+// units; only the dimension needs tracking here.
+fn apply_quantity(
+    expr: &Expr,
+    values: &mut Vec<Quantity>,
+    env: &mut Environment,
+) -> Result<Quantity> {
+    if let Expr::Var(name) = expr {
+        if let (None, Some(unit)) = (env.get(name), Quantity::unit(name)) {
+            return Ok(unit);
+        }
+    }
+    let operands = values.len() - arity(expr);
+    let dimension = dimension(expr, &values[operands..])?;
+    let mut floats: Vec<f64> = values.drain(operands..).map(|value| value.value).collect();
+    Ok(Quantity {
+        value: apply(expr, &mut floats, env)?,
+        dimension,
+    })
+}
+
+fn dimension(expr: &Expr, operands: &[Quantity]) -> Result<Dimension> {
+    if operands.iter().all(Quantity::is_dimensionless) {
+        return Ok(DIMENSIONLESS);
+    }
+    Ok(match (expr, operands) {
+        (Expr::Plus(_) | Expr::Minus(_) | Expr::Cond { .. }, [value]) => value.dimension,
+        (Expr::Add { .. } | Expr::Sub { .. } | Expr::Mod { .. }, [lhs, rhs])
+            if lhs.dimension == rhs.dimension =>
+        {
+            lhs.dimension
+        }
+        (
+            Expr::Eq { .. }
+            | Expr::Ne { .. }
+            | Expr::Lt { .. }
+            | Expr::Le { .. }
+            | Expr::Gt { .. }
+            | Expr::Ge { .. },
+            [lhs, rhs],
+        ) if lhs.dimension == rhs.dimension => DIMENSIONLESS,
+        (Expr::Mul { .. }, [lhs, rhs]) => combine(lhs.dimension, rhs.dimension, 1)?,
+        (Expr::Div { .. } | Expr::FloorDiv { .. }, [lhs, rhs]) => {
+            combine(lhs.dimension, rhs.dimension, -1)?
+        }
+        (Expr::Pow { .. }, [base, exp]) if exp.is_dimensionless() => {
+            scale(base.dimension, exp.value)?
+        }
+        (Expr::Call { name, .. }, [value]) if name == "sqrt" => scale(value.dimension, 0.5)?,
+        (Expr::Call { name, .. }, [first, rest @ ..])
+            if matches!(name.as_str(), "abs" | "min" | "max")
+                && rest.iter().all(|value| value.dimension == first.dimension) =>
+        {
+            first.dimension
+        }
+        _ => return Err(Error::UnitMismatch()),
+    })
+}
+
+// `lhs * rhs` for `sign` 1, `lhs / rhs` for -1.
+fn combine(lhs: Dimension, rhs: Dimension, sign: i8) -> Result<Dimension> {
+    let mut dimension = lhs;
+    for (exp, rhs) in dimension.iter_mut().zip(rhs) {
+        *exp = exp.checked_add(sign * rhs).ok_or(Error::Overflow())?;
+    }
+    Ok(dimension)
+}
+
+// The dimension raised to `exp`, which has to leave every exponent whole:
+// `(4m^2)^0.5` is `2 m`, but `(2m)^0.5` has no unit.
+fn scale(dimension: Dimension, exp: f64) -> Result<Dimension> {
+    let mut scaled = DIMENSIONLESS;
+    for (scaled, base) in scaled.iter_mut().zip(dimension) {
+        let value = f64::from(base) * exp;
+        if value.fract() != 0.0 || value.abs() > f64::from(i8::MAX) {
+            return Err(Error::UnitMismatch());
+        }
+        *scaled = value as i8;
+    }
+    Ok(scaled)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+
+    fn quantity(input: &str) -> Result<Quantity> {
+        parse(input)?.eval_quantity()
+    }
+
+    #[test]
+    fn compatible_quantities_add() {
+        let sum = quantity("3m + 50cm").unwrap();
+        assert_eq!(sum.value, 3.5);
+        assert_eq!(sum.to_string(), "3.5 m");
+        assert_eq!(quantity("1h - 30min").unwrap().value_in("min"), Some(30.0));
+    }
+
+    #[test]
+    fn units_combine_and_convert() {
+        assert_eq!(quantity("10m / 2s").unwrap().to_string(), "5 m/s");
+        assert_eq!(
+            quantity("2kg * 3m / 1s^2").unwrap().to_string(),
+            "6 kg·m/s^2"
+        );
+        assert_eq!(quantity("1 / 2s").unwrap().units(), "1/s");
+        assert_eq!(quantity("3.5m").unwrap().value_in("cm"), Some(350.0));
+        assert_eq!(quantity("sqrt(4m^2)").unwrap().to_string(), "2 m");
+        assert_eq!(quantity("3m").unwrap().value_in("s"), None);
+        assert_eq!(quantity("3m").unwrap().value_in("parsec"), None);
+        assert_eq!(quantity("1km == 1000m").unwrap(), Quantity::from(1.0));
+    }
+
+    #[test]
+    fn mismatched_units_are_an_error() {
+        for input in [
+            "3m + 2s",
+            "1m < 1s",
+            "sin(1m)",
+            "(2m)^0.5",
+            "2 ^ 1m",
+            "max(1m, 1s)",
+        ] {
+            assert!(
+                matches!(quantity(input), Err(Error::UnitMismatch())),
+                "{input}"
+            );
+        }
+    }
+
+    #[test]
+    fn variables_shadow_units() {
+        let mut env = Environment::new();
+        env.set("m", 2.0);
+        let value = parse("3m").unwrap().eval_quantity_in(&mut env).unwrap();
+        assert_eq!(value, Quantity::from(6.0));
+    }
+}
@@ -0,0 +1,280 @@
+use std::fmt::{self, Display, Formatter};
+
+use crate::{
+    error::{Error, Result},
+    eval::{apply, arity, divisor, shift, Environment, IntOverflow},
+    parser::Expr,
+};
+
+// The result of `eval_value`: integral results stay exact in an `i64` for as
+// long as every operation on them keeps them integral.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Value {
+    Int(i64),
+    Float(f64),
+}
+
+// Integral floats within `i64` range become `Int`, everything else `Float`.
+impl From<f64> for Value {
+    fn from(value: f64) -> Self {
+        if value.fract() == 0.0 && value >= i64::MIN as f64 && value < -(i64::MIN as f64) {
+            Value::Int(value as i64)
+        } else {
+            Value::Float(value)
+        }
+    }
+}
+
+impl From<Value> for f64 {
+    fn from(value: Value) -> Self {
+        match value {
+            Value::Int(int) => int as f64,
+            Value::Float(float) => float,
+        }
+    }
+}
+
+impl Display for Value {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Int(int) => write!(f, "{int}"),
+            Value::Float(float) => write!(f, "{float}"),
+        }
+    }
+}
+
+impl Expr {
+    pub fn eval_value(&self) -> Result<Value> {
+        self.eval_value_in(&mut Environment::new())
+    }
+
+    pub fn eval_value_in(&self, env: &mut Environment) -> Result<Value> {
+        self.walk(env, apply_value, |&value| f64::from(value) != 0.0)
+    }
+}
+
+// Applies an operator to `Int` operands where the result is integral and
+// fits, and otherwise falls back to the `f64` evaluator. An `Int` operation
+// that overflows is handled as `config.int_overflow` says, by default by
+// redoing it in floating point.
+fn apply_value(expr: &Expr, values: &mut Vec<Value>, env: &mut Environment) -> Result<Value> {
+    let operands = values.len() - arity(expr);
+    if let Some(value) = apply_int(expr, &values[operands..], env)? {
+        values.truncate(operands);
+        return Ok(value);
+    }
+    let mut floats: Vec<f64> = values.drain(operands..).map(f64::from).collect();
+    let value = apply(expr, &mut floats, env)?;
+    Ok(match expr {
+        Expr::Num(_)
+        | Expr::Var(_)
+        | Expr::Eq { .. }
+        | Expr::Ne { .. }
+        | Expr::Lt { .. }
+        | Expr::Le { .. }
+        | Expr::Gt { .. }
+        | Expr::Ge { .. }
+        | Expr::BitAnd { .. }
+        | Expr::BitOr { .. }
+        | Expr::BitXor { .. }
+        | Expr::Shl { .. }
+        | Expr::Shr { .. } => Value::from(value),
+        _ => Value::Float(value),
+    })
+}
+
+fn apply_int(expr: &Expr, operands: &[Value], env: &mut Environment) -> Result<Option<Value>> {
+    let value = match (expr, operands) {
+        (Expr::Plus(_) | Expr::Cond { .. }, &[value]) => Some(value),
+        (Expr::Assign { name, .. }, &[value @ Value::Int(int)]) => {
+            env.set(name.clone(), int as f64);
+            Some(value)
+        }
+        (Expr::Minus(_), &[Value::Int(int)]) => overflowing(
+            env.config.int_overflow,
+            int.checked_neg(),
+            || int.saturating_neg(),
+            || int.wrapping_neg(),
+        )?
+        .map(Value::Int),
+        (Expr::Factorial(_), &[Value::Int(n)]) if n >= 0 => (2..=n)
+            .try_fold(1i64, |product, k| product.checked_mul(k))
+            .map(Value::Int),
+        (_, &[Value::Int(lhs), Value::Int(rhs)]) => {
+            int_binary(expr, lhs, rhs, env.config.int_overflow)?.map(Value::Int)
+        }
+        _ => None,
+    };
+    Ok(value)
+}
+
+fn int_binary(expr: &Expr, lhs: i64, rhs: i64, policy: IntOverflow) -> Result<Option<i64>> {
+    Ok(match expr {
+        Expr::Add { .. } => overflowing(
+            policy,
+            lhs.checked_add(rhs),
+            || lhs.saturating_add(rhs),
+            || lhs.wrapping_add(rhs),
+        )?,
+        Expr::Sub { .. } => overflowing(
+            policy,
+            lhs.checked_sub(rhs),
+            || lhs.saturating_sub(rhs),
+            || lhs.wrapping_sub(rhs),
+        )?,
+        Expr::Mul { .. } => overflowing(
+            policy,
+            lhs.checked_mul(rhs),
+            || lhs.saturating_mul(rhs),
+            || lhs.wrapping_mul(rhs),
+        )?,
+        Expr::FloorDiv { .. } => {
+            divisor(rhs as f64)?;
+            // `checked_div` truncates toward zero; step down to the floor
+            // when the signs differ and the division is inexact.
+            lhs.checked_div(rhs).map(|quotient| {
+                if quotient * rhs != lhs && (lhs < 0) != (rhs < 0) {
+                    quotient - 1
+                } else {
+                    quotient
+                }
+            })
+        }
+        Expr::Mod { .. } => {
+            divisor(rhs as f64)?;
+            lhs.checked_rem_euclid(rhs)
+        }
+        // A negative exponent gives a fraction. One too large for `u32`
+        // overflows unless the base is 0, 1 or -1, so only its parity
+        // matters.
+        Expr::Pow { .. } if rhs >= 0 => {
+            let exp = u32::try_from(rhs).unwrap_or(u32::MAX - 1 + (rhs % 2) as u32);
+            overflowing(
+                policy,
+                lhs.checked_pow(exp),
+                || lhs.saturating_pow(exp),
+                || lhs.wrapping_pow(exp),
+            )?
+        }
+        Expr::Eq { .. } => Some((lhs == rhs).into()),
+        Expr::Ne { .. } => Some((lhs != rhs).into()),
+        Expr::Lt { .. } => Some((lhs < rhs).into()),
+        Expr::Le { .. } => Some((lhs <= rhs).into()),
+        Expr::Gt { .. } => Some((lhs > rhs).into()),
+        Expr::Ge { .. } => Some((lhs >= rhs).into()),
+        Expr::BitAnd { .. } => Some(lhs & rhs),
+        Expr::BitOr { .. } => Some(lhs | rhs),
+        Expr::BitXor { .. } => Some(lhs ^ rhs),
+        // Invalid shift amounts fall through to the `f64` evaluator, which
+        // reports them.
+        Expr::Shl { .. } => shift(rhs).and_then(|amount| lhs.checked_shl(amount)),
+        Expr::Shr { .. } => shift(rhs).and_then(|amount| lhs.checked_shr(amount)),
+        _ => None,
+    })
+}
+
+// `checked` if the operation did not overflow, and otherwise what `policy`
+// asks for, with `None` meaning the `f64` evaluator takes over.
+fn overflowing(
+    policy: IntOverflow,
+    checked: Option<i64>,
+    saturating: impl FnOnce() -> i64,
+    wrapping: impl FnOnce() -> i64,
+) -> Result<Option<i64>> {
+    Ok(match (checked, policy) {
+        (Some(value), _) => Some(value),
+        (None, IntOverflow::Float) => None,
+        (None, IntOverflow::Saturate) => Some(saturating()),
+        (None, IntOverflow::Wrap) => Some(wrapping()),
+        (None, IntOverflow::Error) => return Err(Error::Overflow()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{eval::EvalConfig, parser::parse};
+
+    fn value(input: &str) -> Value {
+        parse(input).unwrap().eval_value().unwrap()
+    }
+
+    #[test]
+    fn integral_operations_stay_integers() {
+        assert_eq!(value("2+2"), Value::Int(4));
+        assert_eq!(value("7 // 2"), Value::Int(3));
+        assert_eq!(value("2 ^ 10 - 1"), Value::Int(1023));
+    }
+
+    #[test]
+    fn other_operations_give_floats() {
+        assert_eq!(value("5/2"), Value::Float(2.5));
+        assert_eq!(value("2 ^ 0.5"), Value::Float(2f64.sqrt()));
+        assert_eq!(value("0.5 + 0.5"), Value::Float(1.0));
+    }
+
+    #[test]
+    fn integers_that_overflow_become_floats() {
+        assert_eq!(value("2 ^ 62 * 4"), Value::Float(2f64.powi(64)));
+    }
+
+    // `i64::MAX`, built up from integers that a literal can hold exactly.
+    const MAX: &str = "(2 ^ 62 - 1 + 2 ^ 62)";
+
+    fn value_with(input: &str, int_overflow: IntOverflow) -> Result<Value> {
+        let mut env = Environment::with_config(EvalConfig {
+            int_overflow,
+            ..EvalConfig::default()
+        });
+        parse(&input.replace("MAX", MAX))?.eval_value_in(&mut env)
+    }
+
+    #[test]
+    fn overflow_near_max_follows_the_policy() {
+        let cases = [
+            ("MAX", i64::MAX, i64::MAX),
+            ("MAX + 1", i64::MAX, i64::MIN),
+            ("MAX * 2", i64::MAX, -2),
+            ("-MAX - 2", i64::MIN, i64::MAX),
+            ("-(-MAX - 1)", i64::MAX, i64::MIN),
+            ("2 ^ 63", i64::MAX, i64::MIN),
+            ("(-2) ^ 63", i64::MIN, i64::MIN),
+        ];
+        for (input, saturated, wrapped) in cases {
+            let saturate = value_with(input, IntOverflow::Saturate).unwrap();
+            assert_eq!(saturate, Value::Int(saturated), "{input}");
+            let wrap = value_with(input, IntOverflow::Wrap).unwrap();
+            assert_eq!(wrap, Value::Int(wrapped), "{input}");
+        }
+    }
+
+    #[test]
+    fn overflow_can_be_an_error() {
+        assert_eq!(
+            value_with("MAX - 1", IntOverflow::Error).unwrap(),
+            Value::Int(i64::MAX - 1)
+        );
+        for input in ["MAX + 1", "MAX * 2", "-MAX - 2", "2 ^ 63"] {
+            assert!(
+                matches!(
+                    value_with(input, IntOverflow::Error),
+                    Err(Error::Overflow())
+                ),
+                "{input}"
+            );
+        }
+    }
+
+    #[test]
+    fn overflow_defaults_to_floats() {
+        assert_eq!(
+            value_with("MAX + 1", IntOverflow::default()).unwrap(),
+            Value::Float(2f64.powi(63))
+        );
+        // Float operands are never clamped.
+        assert_eq!(
+            value_with("MAX + 1.5", IntOverflow::Saturate).unwrap(),
+            Value::Float(i64::MAX as f64 + 1.5)
+        );
+    }
+}
@@ -0,0 +1,220 @@
+use crate::parser::Expr;
+
+// One hook per kind of node, called by `Expr::accept`. Every hook defaults to
+// visiting the node's operands left to right, so a visitor overrides only the
+// nodes it cares about. The binary operators all default to `visit_binary`,
+// which makes it the single place to handle every one of them alike. An
+// overriding hook that still wants the operands visited calls `accept` on
+// them itself.
+pub trait Visitor {
+    fn visit_num(&mut self, _value: f64) {}
+
+    fn visit_var(&mut self, _name: &str) {}
+
+    fn visit_plus(&mut self, expr: &Expr) {
+        expr.accept(self);
+    }
+
+    fn visit_minus(&mut self, expr: &Expr) {
+        expr.accept(self);
+    }
+
+    fn visit_factorial(&mut self, expr: &Expr) {
+        expr.accept(self);
+    }
+
+    fn visit_binary(&mut self, lhs: &Expr, rhs: &Expr) {
+        lhs.accept(self);
+        rhs.accept(self);
+    }
+
+    fn visit_add(&mut self, lhs: &Expr, rhs: &Expr) {
+        self.visit_binary(lhs, rhs);
+    }
+
+    fn visit_sub(&mut self, lhs: &Expr, rhs: &Expr) {
+        self.visit_binary(lhs, rhs);
+    }
+
+    fn visit_mul(&mut self, lhs: &Expr, rhs: &Expr) {
+        self.visit_binary(lhs, rhs);
+    }
+
+    fn visit_div(&mut self, lhs: &Expr, rhs: &Expr) {
+        self.visit_binary(lhs, rhs);
+    }
+
+    fn visit_floor_div(&mut self, lhs: &Expr, rhs: &Expr) {
+        self.visit_binary(lhs, rhs);
+    }
+
+    fn visit_mod(&mut self, lhs: &Expr, rhs: &Expr) {
+        self.visit_binary(lhs, rhs);
+    }
+
+    fn visit_pow(&mut self, base: &Expr, exp: &Expr) {
+        self.visit_binary(base, exp);
+    }
+
+    fn visit_eq(&mut self, lhs: &Expr, rhs: &Expr) {
+        self.visit_binary(lhs, rhs);
+    }
+
+    fn visit_ne(&mut self, lhs: &Expr, rhs: &Expr) {
+        self.visit_binary(lhs, rhs);
+    }
+
+    fn visit_lt(&mut self, lhs: &Expr, rhs: &Expr) {
+        self.visit_binary(lhs, rhs);
+    }
+
+    fn visit_le(&mut self, lhs: &Expr, rhs: &Expr) {
+        self.visit_binary(lhs, rhs);
+    }
+
+    fn visit_gt(&mut self, lhs: &Expr, rhs: &Expr) {
+        self.visit_binary(lhs, rhs);
+    }
+
+    fn visit_ge(&mut self, lhs: &Expr, rhs: &Expr) {
+        self.visit_binary(lhs, rhs);
+    }
+
+    fn visit_bit_and(&mut self, lhs: &Expr, rhs: &Expr) {
+        self.visit_binary(lhs, rhs);
+    }
+
+    fn visit_bit_or(&mut self, lhs: &Expr, rhs: &Expr) {
+        self.visit_binary(lhs, rhs);
+    }
+
+    fn visit_bit_xor(&mut self, lhs: &Expr, rhs: &Expr) {
+        self.visit_binary(lhs, rhs);
+    }
+
+    fn visit_shl(&mut self, lhs: &Expr, rhs: &Expr) {
+        self.visit_binary(lhs, rhs);
+    }
+
+    fn visit_shr(&mut self, lhs: &Expr, rhs: &Expr) {
+        self.visit_binary(lhs, rhs);
+    }
+
+    fn visit_call(&mut self, _name: &str, args: &[Expr]) {
+        for arg in args {
+            arg.accept(self);
+        }
+    }
+
+    fn visit_assign(&mut self, _name: &str, value: &Expr) {
+        value.accept(self);
+    }
+
+    fn visit_define(&mut self, _name: &str, _param: &str, body: &Expr) {
+        body.accept(self);
+    }
+
+    fn visit_cond(&mut self, test: &Expr, then: &Expr, otherwise: &Expr) {
+        test.accept(self);
+        then.accept(self);
+        otherwise.accept(self);
+    }
+}
+
+impl Expr {
+    // Calls the visitor's hook for this node. Unlike `eval` the walk recurses,
+    // so a very deep tree can overflow the stack; the analyses the crate
+    // itself runs, such as `vars` and `is_constant`, walk `children` instead.
+    pub fn accept<V: Visitor + ?Sized>(&self, visitor: &mut V) {
+        match self {
+            Expr::Num(value) => visitor.visit_num(*value),
+            Expr::Var(name) => visitor.visit_var(name),
+            Expr::Plus(expr) => visitor.visit_plus(expr),
+            Expr::Minus(expr) => visitor.visit_minus(expr),
+            Expr::Factorial(expr) => visitor.visit_factorial(expr),
+            Expr::Add { lhs, rhs } => visitor.visit_add(lhs, rhs),
+            Expr::Sub { lhs, rhs } => visitor.visit_sub(lhs, rhs),
+            Expr::Mul { lhs, rhs } => visitor.visit_mul(lhs, rhs),
+            Expr::Div { lhs, rhs } => visitor.visit_div(lhs, rhs),
+            Expr::FloorDiv { lhs, rhs } => visitor.visit_floor_div(lhs, rhs),
+            Expr::Mod { lhs, rhs } => visitor.visit_mod(lhs, rhs),
+            Expr::Pow { base, exp } => visitor.visit_pow(base, exp),
+            Expr::Eq { lhs, rhs } => visitor.visit_eq(lhs, rhs),
+            Expr::Ne { lhs, rhs } => visitor.visit_ne(lhs, rhs),
+            Expr::Lt { lhs, rhs } => visitor.visit_lt(lhs, rhs),
+            Expr::Le { lhs, rhs } => visitor.visit_le(lhs, rhs),
+            Expr::Gt { lhs, rhs } => visitor.visit_gt(lhs, rhs),
+            Expr::Ge { lhs, rhs } => visitor.visit_ge(lhs, rhs),
+            Expr::BitAnd { lhs, rhs } => visitor.visit_bit_and(lhs, rhs),
+            Expr::BitOr { lhs, rhs } => visitor.visit_bit_or(lhs, rhs),
+            Expr::BitXor { lhs, rhs } => visitor.visit_bit_xor(lhs, rhs),
+            Expr::Shl { lhs, rhs } => visitor.visit_shl(lhs, rhs),
+            Expr::Shr { lhs, rhs } => visitor.visit_shr(lhs, rhs),
+            Expr::Call { name, args } => visitor.visit_call(name, args),
+            Expr::Assign { name, value } => visitor.visit_assign(name, value),
+            Expr::Define { name, param, body } => visitor.visit_define(name, param, body),
+            Expr::Cond {
+                test,
+                then,
+                otherwise,
+            } => visitor.visit_cond(test, then, otherwise),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+
+    // Counts numbers, variables, sums and everything else binary.
+    #[derive(Default)]
+    struct Counts {
+        nums: usize,
+        vars: usize,
+        adds: usize,
+        binary: usize,
+    }
+
+    impl Visitor for Counts {
+        fn visit_num(&mut self, _value: f64) {
+            self.nums += 1;
+        }
+
+        fn visit_var(&mut self, _name: &str) {
+            self.vars += 1;
+        }
+
+        fn visit_binary(&mut self, lhs: &Expr, rhs: &Expr) {
+            self.binary += 1;
+            lhs.accept(self);
+            rhs.accept(self);
+        }
+
+        fn visit_add(&mut self, lhs: &Expr, rhs: &Expr) {
+            self.adds += 1;
+            lhs.accept(self);
+            rhs.accept(self);
+        }
+    }
+
+    #[test]
+    fn visitor_counts_node_kinds() {
+        let mut counts = Counts::default();
+        parse("1 + x * 2 + max(y, 3 - x)")
+            .unwrap()
+            .accept(&mut counts);
+        assert_eq!(counts.nums, 3);
+        assert_eq!(counts.vars, 3);
+        assert_eq!(counts.adds, 2);
+        assert_eq!(counts.binary, 2);
+    }
+
+    #[test]
+    fn default_hooks_visit_every_operand() {
+        let mut counts = Counts::default();
+        parse("-(1 ? x = 2 : |y|!)").unwrap().accept(&mut counts);
+        assert_eq!(counts.nums, 2);
+        assert_eq!(counts.vars, 1);
+    }
+}
@@ -0,0 +1,340 @@
+use std::{
+    fs,
+    io::Write,
+    path::PathBuf,
+    process::{Command, Output, Stdio},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+// Runs the calculator with `args`, feeding it `stdin`.
+fn run(args: &[&str], stdin: &str) -> Output {
+    run_with_env(args, &[], stdin)
+}
+
+// Like `run`, with extra environment variables set. `HOME` defaults to a fresh scratch
+// directory so that the REPL history never touches the real one.
+fn run_with_env(args: &[&str], vars: &[(&str, &str)], stdin: &str) -> Output {
+    static RUNS: AtomicUsize = AtomicUsize::new(0);
+    let home = scratch_dir(&format!("home-{}", RUNS.fetch_add(1, Ordering::Relaxed)));
+    let mut child = Command::new(env!("CARGO_BIN_EXE_rust-calculator"))
+        .args(args)
+        .env_remove("RUSTCALC_PROMPT")
+        .env_remove("RUSTCALC_OUTPUT")
+        .env("HOME", home)
+        .envs(vars.iter().copied())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(stdin.as_bytes())
+        .unwrap();
+    child.wait_with_output().unwrap()
+}
+
+// Returns an empty directory unique to this test process and `name`.
+fn scratch_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("rustcalc-{}-{name}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn stdout(output: &Output) -> &str {
+    std::str::from_utf8(&output.stdout).unwrap()
+}
+
+fn stderr(output: &Output) -> &str {
+    std::str::from_utf8(&output.stderr).unwrap()
+}
+
+#[test]
+fn ans_holds_the_previous_result() {
+    let output = run(&["--quiet"], "1 + 2\nans * 10\n");
+    assert_eq!(stdout(&output), "3\n30\n");
+}
+
+#[test]
+fn ans_is_undefined_on_the_first_line() {
+    let output = run(&["--quiet"], "ans\n");
+    assert_eq!(stdout(&output), "");
+    assert_eq!(stderr(&output), "ERROR: Undefined variable: \"ans\"\n");
+}
+
+#[test]
+fn evaluates_an_argument_once() {
+    let output = run(&["1 + 2 * 3"], "");
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "7\n");
+    assert_eq!(stderr(&output), "");
+}
+
+#[test]
+fn an_argument_that_fails_prints_to_stderr() {
+    let output = run(&["1 +"], "");
+    assert!(!output.status.success());
+    assert_eq!(stdout(&output), "");
+    assert_eq!(
+        stderr(&output),
+        "ERROR: Unexpected end of input, expected an operand\n1 +\n   ^\n"
+    );
+}
+
+#[test]
+fn quiet_prints_only_results() {
+    let output = run(&["--quiet"], "2+2\nx = 3\nx * 2\n");
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "4\n3\n6\n");
+}
+
+#[test]
+fn results_are_numbered_without_quiet() {
+    let output = run(&[], "2+2\n2*3\n");
+    assert!(stdout(&output).contains("[1]: 4\n[2]: 6\n"));
+}
+
+#[test]
+fn quit_ends_the_session() {
+    let output = run(&["--quiet"], "1\n:quit\n2\n");
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "1\n");
+}
+
+#[test]
+fn clear_forgets_variables() {
+    let output = run(&["--quiet"], "x = 2\n:vars\n:clear\n:vars\nx\n");
+    assert_eq!(stdout(&output), "2\nans = 2\nx = 2\n");
+    assert_eq!(stderr(&output), "ERROR: Undefined variable: \"x\"\n");
+}
+
+#[test]
+fn empty_stdin_exits_cleanly() {
+    let output = run(&[], "");
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "\n");
+    assert_eq!(stderr(&output), "");
+}
+
+#[test]
+fn input_without_a_final_newline_is_evaluated() {
+    let output = run(&["--quiet"], "1 + 1");
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "2\n");
+}
+
+// `units` takes precedence over `bigdecimal` when both are on.
+#[cfg(feature = "units")]
+#[test]
+fn results_carry_units() {
+    let output = run(&["--quiet"], "3m + 50cm\n10m / 2s\n3m + 2s\n");
+    assert_eq!(stdout(&output), "3.5 m\n5 m/s\n");
+    assert_eq!(stderr(&output), "ERROR: Incompatible units\n");
+    assert_eq!(run(&["3m + 2s"], "").status.code(), Some(3));
+}
+
+#[cfg(all(feature = "bigdecimal", not(feature = "units")))]
+#[test]
+fn bigdecimal_results_are_exact() {
+    let output = run(&["--quiet"], "0.1 + 0.2\n0.1 + 0.2 == 0.3\n");
+    assert_eq!(stdout(&output), "0.3\n1\n");
+}
+
+#[test]
+fn statements_using_i_are_complex() {
+    let output = run(&["--quiet"], "(1 + 2i)^2\nsqrt(-4) + i\n");
+    assert_eq!(stdout(&output), "-3 + 4i\n3i\n");
+}
+
+#[test]
+fn a_variable_named_i_is_real() {
+    let output = run(&["--quiet"], "i = 2\ni * 3\n");
+    assert_eq!(stdout(&output), "2\n6\n");
+}
+
+#[test]
+fn history_is_saved_to_home() {
+    let home = scratch_dir("history");
+    let home_str = home.to_str().unwrap();
+    let _ = fs::remove_file(home.join(".rustcalculator_history"));
+    run_with_env(&[], &[("HOME", home_str)], "1+1\n\n2*3\n");
+    let history = fs::read_to_string(home.join(".rustcalculator_history")).unwrap();
+    assert!(history.lines().any(|line| line == "1+1"));
+    assert!(history.lines().any(|line| line == "2*3"));
+    assert!(!history.lines().any(|line| line.is_empty()));
+}
+
+#[test]
+fn quiet_does_not_save_history() {
+    let home = scratch_dir("quiet-history");
+    let home_str = home.to_str().unwrap();
+    let _ = fs::remove_file(home.join(".rustcalculator_history"));
+    run_with_env(&["--quiet"], &[("HOME", home_str)], "1+1\n");
+    assert!(!home.join(".rustcalculator_history").exists());
+}
+
+#[test]
+fn results_print_without_a_trailing_fraction() {
+    let output = run(&["--quiet"], "8 / 2\n1 / 2\n-0\n");
+    assert_eq!(stdout(&output), "4\n0.5\n0\n");
+}
+
+#[test]
+fn parse_errors_exit_with_2() {
+    for input in ["1 +", "(1", "2 3"] {
+        let output = run(&[input], "");
+        assert_eq!(output.status.code(), Some(2), "{input}");
+        assert_eq!(stdout(&output), "", "{input}");
+        assert!(stderr(&output).starts_with("ERROR: "), "{input}");
+    }
+}
+
+#[test]
+fn evaluation_errors_exit_with_3() {
+    for input in ["1/0", "0 * inf", "sqrt(-1)", "x + 1"] {
+        let output = run(&[input], "");
+        assert_eq!(output.status.code(), Some(3), "{input}");
+        assert_eq!(stdout(&output), "", "{input}");
+    }
+}
+
+#[test]
+fn unknown_options_exit_with_1() {
+    let output = run(&["--bogus"], "");
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(stderr(&output), "ERROR: Unknown option \"--bogus\"\n");
+}
+
+#[test]
+fn ans_indexes_the_numbered_results() {
+    let output = run(&[], "1+1\n10\nans[1] + ans[2]\nans[9]\n");
+    assert_eq!(stdout(&output), "[1]: 2\n[2]: 10\n[3]: 12\n\n");
+    assert_eq!(stderr(&output), "ERROR: No such result: [9]\n");
+}
+
+#[test]
+fn functions_can_be_defined_in_the_repl() {
+    let output = run(&["--quiet"], "f(x) = x * x\nf(5)\nf(x) = x + 1\nf(5)\n");
+    assert_eq!(stdout(&output), "25\n6\n");
+    assert_eq!(stderr(&output), "");
+}
+
+#[test]
+fn nan_is_rejected_in_one_shot_mode() {
+    let output = run(&["nan + 1"], "");
+    assert_eq!(output.status.code(), Some(3));
+    assert_eq!(stderr(&output), "ERROR: Result is not a number\n");
+    let output = run(&["--quiet"], "nan + 1\n");
+    // A decimal has no NaN, so the `bigdecimal` build rejects it everywhere.
+    if cfg!(all(feature = "bigdecimal", not(feature = "units"))) {
+        assert_eq!(stderr(&output), "ERROR: Result is not a number\n");
+    } else {
+        assert_eq!(stdout(&output), "NaN\n");
+    }
+}
+
+#[test]
+fn output_format_comes_from_the_environment() {
+    let vars = [("RUSTCALC_OUTPUT", "{i} => {result}")];
+    let output = run_with_env(&[], &vars, "1+1\n2*3\n");
+    assert_eq!(stdout(&output), "1 => 2\n2 => 6\n\n");
+    let output = run_with_env(&["--quiet"], &vars, "1+1\n");
+    assert_eq!(stdout(&output), "2\n");
+}
+
+#[test]
+fn ast_prints_without_evaluating() {
+    let output = run(&[], ":ast 1 + 2 * 3\n:ast x = 1 / 0\nx\n5\n");
+    assert_eq!(stdout(&output), "(+ 1 (* 2 3))\n(= x (/ 1 0))\n[1]: 5\n\n");
+    assert_eq!(stderr(&output), "ERROR: Undefined variable: \"x\"\n");
+}
+
+// Writes `contents` to a file in a scratch directory and returns its path.
+fn script(name: &str, contents: &str) -> String {
+    let path = scratch_dir("scripts").join(name);
+    fs::write(&path, contents).unwrap();
+    path.to_str().unwrap().to_owned()
+}
+
+#[test]
+fn file_results_are_numbered_and_errors_do_not_stop_the_rest() {
+    let path = script(
+        "mixed.txt",
+        "1 + 1\n\n# comment\nx = 3\n1 +\nx * 2\n1 / 0\nans\n",
+    );
+    let output = run(&["--file", &path], "");
+    assert_eq!(stdout(&output), "[1]: 2\n[2]: 3\n[3]: 6\n[4]: 6\n");
+    assert_eq!(
+        stderr(&output),
+        "ERROR: line 5: Unexpected end of input, expected an operand\n1 +\n   ^\n\
+         ERROR: line 7: Division by zero\n"
+    );
+    // The first failing line decides the exit code.
+    assert_eq!(output.status.code(), Some(2));
+    let output = run(&["--file", &path, "--quiet"], "");
+    assert_eq!(stdout(&output), "2\n3\n6\n6\n");
+}
+
+#[test]
+fn file_exit_codes() {
+    let path = script("valid.txt", "f(x) = x * 2\nf(3); f(4)\n");
+    let output = run(&["--file", &path], "");
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "[1]: 6\n[2]: 8\n");
+    let path = script("eval-error.txt", "sqrt(-1)\n1 +\n");
+    assert_eq!(run(&["--file", &path], "").status.code(), Some(3));
+}
+
+#[test]
+fn file_must_be_readable_and_alone() {
+    let output = run(&["--file", "/nonexistent/exprs.txt"], "");
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).starts_with("ERROR: Could not read \"/nonexistent/exprs.txt\""));
+    let output = run(&["--file"], "");
+    assert_eq!(stderr(&output), "ERROR: --file needs a path\n");
+    let path = script("alone.txt", "1\n");
+    let output = run(&["--file", &path, "1 + 1"], "");
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(stderr(&output), "ERROR: --file takes no expression\n");
+}
+
+#[test]
+fn long_chains_do_not_overflow_the_stack() {
+    let chain = vec!["1"; 200_000].join("+");
+    let output = run(&["--quiet"], &format!("{chain}\n"));
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(stdout(&output), "200000\n");
+    let output = run(&[], &format!(":ast {chain}\n"));
+    assert!(stdout(&output).starts_with("(+ (+ (+"));
+    let path = script("chain.txt", &chain);
+    let output = run(&["--file", &path, "--quiet"], "");
+    assert_eq!(stdout(&output), "200000\n");
+}
+
+#[test]
+fn file_results_are_numbered_as_ans_reads_them() {
+    let path = script("ans.txt", "x = 3\n\n1 +\n2; 4\nans[1] + ans[2] + ans[3]\n");
+    let output = run(&["--file", &path], "");
+    assert_eq!(stdout(&output), "[1]: 3\n[2]: 2\n[3]: 4\n[4]: 9\n");
+    assert!(stderr(&output).starts_with("ERROR: line 3: "));
+}
+
+// Without a terminal on stdin rustyline reads plain lines and never writes
+// the prompt, so what `RUSTCALC_PROMPT` sets cannot be seen from a pipe.
+// Setting it must still leave the output alone.
+#[test]
+fn prompt_is_not_written_to_a_pipe() {
+    let vars = [("RUSTCALC_PROMPT", "calc> ")];
+    let output = run_with_env(&[], &vars, "1+1\n2*3\n");
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "[1]: 2\n[2]: 6\n\n");
+    assert_eq!(stderr(&output), "");
+    let vars = [
+        ("RUSTCALC_PROMPT", "calc> "),
+        ("RUSTCALC_OUTPUT", "{result}"),
+    ];
+    let output = run_with_env(&[], &vars, "1+1\n");
+    assert_eq!(stdout(&output), "2\n\n");
+}
@@ -0,0 +1,40 @@
+use rust_calculator::{eval_program, eval_str, parser, Error};
+
+#[test]
+fn evaluates_in_one_call() {
+    assert_eq!(eval_str("1 + 2 * 3").unwrap(), 7.0);
+    assert_eq!(eval_str("(1 + 2) * 3").unwrap(), 9.0);
+}
+
+#[test]
+fn reports_parse_and_evaluation_errors() {
+    assert!(matches!(eval_str("1 +"), Err(Error::UnexpectedEof(_))));
+    assert!(matches!(eval_str("1 / 0"), Err(Error::DivisionByZero())));
+}
+
+#[test]
+fn parser_is_public() {
+    let expr = parser::parse("2 ^ 10").unwrap();
+    assert_eq!(expr.eval().unwrap(), 1024.0);
+}
+
+#[test]
+fn evaluates_each_statement_of_a_program() {
+    assert_eq!(eval_program("1+1; 2*3; 4-1").unwrap(), [2.0, 6.0, 3.0]);
+    assert_eq!(eval_program("x = 2; x * 3").unwrap(), [2.0, 6.0]);
+}
+
+#[test]
+fn empty_statements_are_skipped() {
+    assert_eq!(eval_program("1;").unwrap(), [1.0]);
+    assert_eq!(eval_program("1;;2").unwrap(), [1.0, 2.0]);
+    assert!(eval_program("").unwrap().is_empty());
+}
+
+#[test]
+fn a_failing_statement_fails_the_program() {
+    assert!(matches!(
+        eval_program("1; 1/0; 2"),
+        Err(Error::DivisionByZero())
+    ));
+}